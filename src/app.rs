@@ -1,32 +1,54 @@
 use std::{path::PathBuf, time::Duration};
 
-use eframe::{epaint::Shadow, Frame};
+use eframe::epaint::Shadow;
 use egui::{
-    menu, CentralPanel, Color32, Margin, Pos2, Rounding, SidePanel, Stroke, TopBottomPanel, Window,
+    menu, Area, Button, CentralPanel, Color32, Margin, Pos2, ProgressBar, Rounding, SidePanel,
+    Slider, Stroke, TopBottomPanel, Window,
 };
-use image::{ImageBuffer, RgbaImage};
+use image::RgbaImage;
 
-use crate::helpers::no_nonsense_sub;
+use camouflage_images::{
+    camouflage::CamouflageParams,
+    quantization::smoothing::BilateralParams,
+    segmentation::{
+        criterion::SegmentationMode,
+        label_map::{colorize, LabelMap},
+    },
+};
 
 use self::{
     foreground::Foreground,
     image_wrapper::ImageWrapper,
-    my_menu::{load_image, open_image},
+    my_menu::{load_image, open_image, open_labels},
+    worker::{CamouflageWorker, WorkerStatus},
 };
 
 pub mod foreground;
 pub mod image_wrapper;
 pub mod my_menu;
+pub mod worker;
 
 #[derive(Default)]
 pub struct CamouflageImages {
     background: Option<ImageWrapper>,
     foreground: Option<Foreground>,
+    camouflaged: Option<ImageWrapper>,
+    show_camouflaged: bool,
+    /// Colours of the segments of an imported label map
+    segments: Option<ImageWrapper>,
+    show_segments: bool,
+    params: CamouflageParams,
+    worker: Option<CamouflageWorker>,
+    /// Why the last camouflage failed
+    error: Option<String>,
 }
 
 impl CamouflageImages {
     pub const DEFAULT_POS: Pos2 = Pos2 { x: 8.0, y: 32.0 };
 
+    /// Alpha of the segment colours, letting the image under them show through
+    const SEGMENTS_ALPHA: u8 = 160;
+
     const FOREGROUND_MARGIN: Margin = Margin {
         left: 8.0,
         right: 8.0,
@@ -82,6 +104,13 @@ impl CamouflageImages {
                             ui.close_menu();
                         };
                     });
+
+                    if ui.button("Open label map").clicked() {
+                        let labels = open_labels("Select a label map");
+                        self.update_segments(labels, ctx);
+
+                        ui.close_menu();
+                    }
                 });
             });
         });
@@ -97,6 +126,28 @@ impl CamouflageImages {
                         ui.image(&background.texture, background.texture.size_vec2());
                     }
 
+                    if self.show_camouflaged {
+                        if let Some(ref camouflaged) = self.camouflaged {
+                            Area::new("camouflage_layer")
+                                .fixed_pos(CamouflageImages::DEFAULT_POS)
+                                .movable(false)
+                                .show(ctx, |ui| {
+                                    ui.image(&camouflaged.texture, camouflaged.size);
+                                });
+                        }
+                    }
+
+                    if self.show_segments {
+                        if let Some(ref segments) = self.segments {
+                            Area::new("segments_layer")
+                                .fixed_pos(CamouflageImages::DEFAULT_POS)
+                                .movable(false)
+                                .show(ctx, |ui| {
+                                    ui.image(&segments.texture, segments.size);
+                                });
+                        }
+                    }
+
                     if let Some(ref mut foreground) = self.foreground {
                         foreground.draw_foreground_layer(ctx);
                         let mut next_pos: Pos2 = Default::default();
@@ -123,6 +174,34 @@ impl CamouflageImages {
     fn update_background(&mut self, new_img: Option<RgbaImage>, ctx: &egui::Context) {
         if let Some(img) = new_img {
             self.background = Some(ImageWrapper::new(img, String::from("background"), ctx));
+            self.camouflaged = None;
+        }
+    }
+
+    fn update_camouflaged(&mut self, img: RgbaImage, ctx: &egui::Context) {
+        match self.camouflaged {
+            Some(ref mut camouflaged) => camouflaged.update(img),
+            None => {
+                self.camouflaged = Some(ImageWrapper::new(img, String::from("camouflaged"), ctx));
+            }
+        }
+
+        self.show_camouflaged = true;
+    }
+
+    fn update_segments(&mut self, labels: Option<LabelMap>, ctx: &egui::Context) {
+        if let Some(labels) = labels {
+            let mut img = colorize(&labels);
+            for pixel in img.pixels_mut().filter(|p| p[3] > 0) {
+                pixel[3] = CamouflageImages::SEGMENTS_ALPHA;
+            }
+
+            match self.segments {
+                Some(ref mut segments) => segments.update(img),
+                None => self.segments = Some(ImageWrapper::new(img, String::from("segments"), ctx)),
+            }
+
+            self.show_segments = true;
         }
     }
 
@@ -142,6 +221,73 @@ impl CamouflageImages {
         }
     }
 
+    fn poll_worker(&mut self, ui: &mut egui::Ui, ctx: &egui::Context) {
+        let Some(ref mut worker) = self.worker else {
+            return;
+        };
+
+        match worker.poll() {
+            WorkerStatus::Running(stage) => {
+                ui.add(
+                    ProgressBar::new(stage.progress())
+                        .text(stage.name())
+                        .animate(true),
+                );
+
+                if worker.is_cancelled() {
+                    ui.label("Cancelling...");
+                } else if ui.button("Cancel").clicked() {
+                    worker.cancel();
+                }
+            }
+            WorkerStatus::Done(img) => {
+                self.worker = None;
+                self.update_camouflaged(img, ctx);
+            }
+            WorkerStatus::Failed(err) => {
+                self.worker = None;
+                self.error = Some(err);
+            }
+            WorkerStatus::Stopped => {
+                self.worker = None;
+            }
+        }
+    }
+
+    fn settings(&mut self, ui: &mut egui::Ui) {
+        ui.collapsing("Settings", |ui| {
+            // The other segmentation modes ignore the smoothing
+            let by_tone = self.params.segmentation == SegmentationMode::Tone;
+
+            ui.add_enabled_ui(by_tone, |ui| {
+                let mut smooth = self.params.prefilter.is_some();
+
+                if ui
+                    .checkbox(&mut smooth, "Smooth before quantizing")
+                    .changed()
+                {
+                    self.params.prefilter = smooth.then(BilateralParams::default);
+                }
+
+                if let Some(ref mut prefilter) = self.params.prefilter {
+                    // Both spreads must stay positive and finite
+                    ui.add(
+                        Slider::new(&mut prefilter.spatial_sigma, 0.5..=10.0)
+                            .clamp_to_range(true)
+                            .text("Spatial spread"),
+                    );
+                    ui.add(
+                        Slider::new(&mut prefilter.range_sigma, 1.0..=100.0)
+                            .clamp_to_range(true)
+                            .text("Tone spread"),
+                    );
+                }
+            })
+            .response
+            .on_disabled_hover_text("Only used when segmenting by tone");
+        });
+    }
+
     pub fn side(&mut self, ctx: &egui::Context) {
         SidePanel::right("apply_menu").show(ctx, |ui| {
             ui.vertical(|ui| {
@@ -152,10 +298,38 @@ impl CamouflageImages {
 
                     ui.separator();
 
-                    if ui.button("CAMOUFLAGE").clicked() {
-                        println!("Here goes nothing.");
+                    let idle = self.worker.is_none();
+
+                    if ui.add_enabled(idle, Button::new("CAMOUFLAGE")).clicked() {
+                        if let Some(ref background) = self.background {
+                            let (img, pos) = foreground.placement();
+
+                            self.error = None;
+                            self.worker = Some(CamouflageWorker::spawn(
+                                background.img.clone(),
+                                img,
+                                pos,
+                                self.params.clone(),
+                            ));
+                        }
                     }
                 }
+
+                self.settings(ui);
+
+                self.poll_worker(ui, ctx);
+
+                if let Some(ref err) = self.error {
+                    ui.colored_label(Color32::RED, err);
+                }
+
+                if self.camouflaged.is_some() {
+                    ui.checkbox(&mut self.show_camouflaged, "Show camouflage");
+                }
+
+                if self.segments.is_some() {
+                    ui.checkbox(&mut self.show_segments, "Show segments");
+                }
             });
         });
     }
@@ -163,10 +337,17 @@ impl CamouflageImages {
 
 impl eframe::App for CamouflageImages {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        ctx.request_repaint_after(Duration::MILLISECOND);
+        ctx.request_repaint_after(Duration::from_millis(1));
 
         CamouflageImages::menu(self, ctx);
         CamouflageImages::side(self, ctx);
         CamouflageImages::central(self, ctx);
     }
 }
+
+pub fn no_nonsense_sub(lhs: Pos2, rhs: Pos2) -> Pos2 {
+    Pos2 {
+        x: lhs.x - rhs.x,
+        y: lhs.y - rhs.y,
+    }
+}
@@ -1,9 +1,7 @@
-use egui::{Area, Context, Pos2, Vec2, Window};
+use egui::{Area, Context, Pos2};
 use image::{imageops::overlay, RgbaImage};
 
-use crate::helpers::{no_nonsense_sub, Coordinates};
-
-use super::{image_wrapper::ImageWrapper, CamouflageImages};
+use super::{image_wrapper::ImageWrapper, no_nonsense_sub, CamouflageImages};
 
 pub struct Foreground {
     pub window: ImageWrapper, /// The interactable window that the user manipulates
@@ -49,6 +47,13 @@ impl Foreground {
         self.layer.reload_texture();
     }
 
+    /// The foreground as it is placed over the background, along with its position there
+    pub fn placement(&mut self) -> (RgbaImage, (i64, i64)) {
+        self.correct_pos();
+
+        (self.window.scale_img(), (self.pos.x as _, self.pos.y as _))
+    }
+
     pub fn draw_foreground_layer(&mut self, ctx: &Context) {
         Area::new("foreground_layer")
             .fixed_pos(CamouflageImages::DEFAULT_POS)
@@ -58,11 +63,6 @@ impl Foreground {
             });
     }
 
-    pub fn reset_layer(&mut self, new_size: (u32, u32)) {
-        let empty_image = RgbaImage::new(new_size.0, new_size.1);
-        self.layer.update(empty_image);
-    }
-
     #[inline]
     pub fn update(&mut self, img: RgbaImage) {
         self.window.update(img);
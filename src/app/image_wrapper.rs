@@ -1,7 +1,4 @@
-use std::path::PathBuf;
-
-use eframe::glow::LINEAR;
-use egui::{ColorImage, Context, Pos2, TextureHandle, TextureOptions, Vec2};
+use egui::{ColorImage, Context, TextureHandle, TextureOptions, Vec2};
 use image::{imageops::resize, RgbaImage};
 
 pub struct ImageWrapper {
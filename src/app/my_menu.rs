@@ -3,6 +3,8 @@ use std::path::PathBuf;
 use image::RgbaImage;
 use rfd::FileDialog;
 
+use camouflage_images::segmentation::label_map::{open_label_map, LabelMap};
+
 fn load_dialog(title: &str) -> Option<PathBuf> {
     FileDialog::new()
         .add_filter("General images", &["png", "jpg", "jpeg", "gif", "webp"])
@@ -28,3 +30,13 @@ pub fn open_image(title: &str) -> Option<RgbaImage> {
         None => None,
     }
 }
+
+/// Label map saved by the `segment` subcommand
+pub fn open_labels(title: &str) -> Option<LabelMap> {
+    let path = FileDialog::new()
+        .add_filter("Label maps", &["png"])
+        .set_title(title)
+        .pick_file()?;
+
+    open_label_map(path).ok()
+}
@@ -0,0 +1,100 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Receiver, TryRecvError},
+        Arc,
+    },
+    thread,
+};
+
+use image::{DynamicImage, RgbaImage};
+
+use camouflage_images::camouflage::{
+    camouflage_img_with_progress, CamouflageError, CamouflageParams, Stage,
+};
+
+enum Message {
+    Stage(Stage),
+    Done(RgbaImage),
+    Failed(String),
+}
+
+pub enum WorkerStatus {
+    Running(Stage),
+    Done(RgbaImage),
+    Failed(String),
+    /// Cancelled by the user, or the thread died
+    Stopped,
+}
+
+/// Runs the camouflage pipeline out of the UI thread
+pub struct CamouflageWorker {
+    receiver: Receiver<Message>,
+    cancel: Arc<AtomicBool>,
+    stage: Stage,
+}
+
+impl CamouflageWorker {
+    pub fn spawn(
+        background: RgbaImage,
+        foreground: RgbaImage,
+        pos: (i64, i64),
+        params: CamouflageParams,
+    ) -> Self {
+        let (sender, receiver) = channel();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let cancelled = cancel.clone();
+
+        thread::spawn(move || {
+            let i_b = DynamicImage::ImageRgba8(background);
+            let i_f = DynamicImage::ImageRgba8(foreground);
+
+            // The long stages report themselves several times
+            let mut current = None;
+            let res = camouflage_img_with_progress(&i_b, &i_f, pos, &params, |stage| {
+                if cancelled.load(Ordering::Relaxed) {
+                    return false;
+                }
+
+                current.replace(stage) == Some(stage) || sender.send(Message::Stage(stage)).is_ok()
+            });
+
+            let message = match res {
+                Ok(img) => Message::Done(img.into_rgba8()),
+                Err(CamouflageError::Cancelled) => return,
+                Err(err) => Message::Failed(err.to_string()),
+            };
+            let _ = sender.send(message);
+        });
+
+        Self {
+            receiver,
+            cancel,
+            stage: Stage::Smooth,
+        }
+    }
+
+    /// The pipeline stops at the start of its next stage, swap move or synthesized patch
+    #[inline]
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed)
+    }
+
+    /// Drains the messages sent by the thread so far
+    pub fn poll(&mut self) -> WorkerStatus {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(Message::Stage(stage)) => self.stage = stage,
+                Ok(Message::Done(img)) => return WorkerStatus::Done(img),
+                Ok(Message::Failed(err)) => return WorkerStatus::Failed(err),
+                Err(TryRecvError::Empty) => return WorkerStatus::Running(self.stage),
+                Err(TryRecvError::Disconnected) => return WorkerStatus::Stopped,
+            }
+        }
+    }
+}
@@ -1,32 +1,556 @@
-use image::{DynamicImage, GenericImageView};
+pub mod labeling;
+pub mod remapping;
+
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+};
+
+use image::{
+    imageops::{crop_imm, replace},
+    DynamicImage, GrayAlphaImage, ImageBuffer, Pixel, Rgba, RgbaImage,
+};
+use petgraph::graph::NodeIndex;
 
 use crate::{
-    graphs::mount_graph, helpers::Crop, quantization::quantize_by_tones,
-    segmentation::ImgSegmentation,
+    graphs::mount_graph,
+    helpers::Crop,
+    quantization::{
+        smoothing::{bilateral_with_progress, BilateralParams},
+        Levels, QuantizationError, Quantizer,
+    },
+    segmentation::{
+        criterion::SegmentationMode,
+        merging::merge_small,
+        slic::{Slic, SlicParams},
+        Connectivity, ImageSegments, ImgSegmentation, Segment, SegmentationError, Segmenter,
+    },
+    synthesis::{SynthesisParams, TextureSynthesis},
+};
+
+use self::{
+    labeling::alpha_beta_swap_with_progress,
+    remapping::{remap_luminance, ToneMap},
 };
 
-fn camouflage_img(i_b: &DynamicImage, i_f: &DynamicImage, _pos: (u32, u32)) -> DynamicImage {
-    let camouflaged = DynamicImage::new_rgba8(i_b.width(), i_b.height());
+/// Weights of the energy minimized when labeling foreground segments with background ones, and
+/// how the segments are found
+#[derive(Clone)]
+pub struct CamouflageParams {
+    /// Preservation of the contrast between neighbouring foreground segments
+    pub immediacy: f64,
+    /// Matching between each foreground tone and the background tone it takes
+    pub standout: f64,
+    /// Penalty for neighbouring foreground segments taking different background segments
+    pub smoothness: f64,
+    /// Edge-preserving smoothing of the luminance before quantizing it, none without. Only done
+    /// when segmenting by tone.
+    pub prefilter: Option<BilateralParams>,
+    /// Quantization of the luminance, only done when segmenting by tone
+    pub background_quantizer: Quantizer,
+    pub foreground_quantizer: Quantizer,
+    pub background_levels: Levels,
+    pub foreground_levels: Levels,
+    /// Used both to segment the images and to find touching foreground segments
+    pub connectivity: Connectivity,
+    pub segmentation: SegmentationMode,
+    /// Segments with fewer pixels are merged into a touching one, none are with 0
+    pub min_area: usize,
+    /// Closest background segments each foreground segment may be labeled with
+    pub neighbours: usize,
+}
 
-    // Quantization and segmentation
+impl Default for CamouflageParams {
+    fn default() -> Self {
+        Self {
+            immediacy: 2.0,
+            standout: 1.0,
+            smoothness: 0.01,
+            prefilter: None,
+            background_quantizer: Quantizer::Uniform,
+            foreground_quantizer: Quantizer::Uniform,
+            background_levels: Levels::Auto,
+            foreground_levels: Levels::Auto,
+            connectivity: Connectivity::Four,
+            segmentation: SegmentationMode::Tone,
+            min_area: 0,
+            neighbours: 6,
+        }
+    }
+}
 
-    // Converting to luminance
-    let mut lu_b = i_b.to_luma_alpha8();
-    let mut lu_f = i_f.to_luma_alpha8();
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Stage {
+    Smooth,
+    Quantize,
+    Segment,
+    Crop,
+    Graph,
+    Label,
+    Remap,
+    Synthesize,
+}
 
-    // Applying quantization
-    quantize_by_tones(&mut lu_b);
-    quantize_by_tones(&mut lu_f);
+impl Stage {
+    pub const ALL: [Stage; 8] = [
+        Stage::Smooth,
+        Stage::Quantize,
+        Stage::Segment,
+        Stage::Crop,
+        Stage::Graph,
+        Stage::Label,
+        Stage::Remap,
+        Stage::Synthesize,
+    ];
 
-    // Segmenting images
-    let seg_b = ImgSegmentation::segment_img(&lu_b);
-    let seg_f = ImgSegmentation::segment_img(&lu_f);
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Smooth => "Smoothing",
+            Stage::Quantize => "Quantizing",
+            Stage::Segment => "Segmenting",
+            Stage::Crop => "Cropping",
+            Stage::Graph => "Creating graph",
+            Stage::Label => "Labeling",
+            Stage::Remap => "Remapping luminance",
+            Stage::Synthesize => "Synthesizing texture",
+        }
+    }
+
+    /// Fraction of the pipeline done when the stage starts
+    pub fn progress(&self) -> f32 {
+        let position = Stage::ALL.iter().position(|s| s == self).unwrap();
+        position as f32 / Stage::ALL.len() as f32
+    }
+}
+
+#[derive(Debug)]
+pub enum CamouflageError {
+    /// The progress callback asked to stop
+    Cancelled,
+    /// Foreground segments may take no background segment
+    NoNeighbours,
+    Quantization(QuantizationError),
+    Segmentation(SegmentationError),
+}
+
+impl Display for CamouflageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CamouflageError::Cancelled => write!(f, "camouflage cancelled"),
+            CamouflageError::NoNeighbours => {
+                write!(f, "each foreground segment needs at least one neighbour")
+            }
+            CamouflageError::Quantization(err) => err.fmt(f),
+            CamouflageError::Segmentation(err) => err.fmt(f),
+        }
+    }
+}
+
+impl Error for CamouflageError {}
+
+impl From<QuantizationError> for CamouflageError {
+    fn from(err: QuantizationError) -> Self {
+        CamouflageError::Quantization(err)
+    }
+}
+
+impl From<SegmentationError> for CamouflageError {
+    fn from(err: SegmentationError) -> Self {
+        CamouflageError::Segmentation(err)
+    }
+}
+
+/// Hides the foreground in the background, with its top left corner at `pos` in background
+/// coordinates. Parts of the foreground outside the background are ignored.
+pub fn camouflage_img(
+    i_b: &DynamicImage,
+    i_f: &DynamicImage,
+    pos: (i64, i64),
+    params: &CamouflageParams,
+) -> Result<DynamicImage, CamouflageError> {
+    camouflage_img_with_progress(i_b, i_f, pos, params, |_| true)
+}
+
+/// Same as [`camouflage_img`], calling `progress` before each stage, and again before each swap
+/// move and patch of the labeling and synthesis stages.
+/// Stops with [`CamouflageError::Cancelled`] as soon as `progress` returns false.
+pub fn camouflage_img_with_progress<F>(
+    i_b: &DynamicImage,
+    i_f: &DynamicImage,
+    pos: (i64, i64),
+    params: &CamouflageParams,
+    mut progress: F,
+) -> Result<DynamicImage, CamouflageError>
+where
+    F: FnMut(Stage) -> bool,
+{
+    if params.neighbours == 0 {
+        return Err(CamouflageError::NoNeighbours);
+    }
+
+    // Smoothing, quantizing and segmenting both images
+    let (seg_b, seg_f, full_f) = segment_images(i_b, i_f, pos, params, &mut progress)?;
+    let mut camouflaged = i_b.to_rgba8();
 
     // Cropping images
+    enter(&mut progress, Stage::Crop)?;
     let seg_b = seg_f.crop(seg_b);
 
+    if seg_f.is_empty() || seg_b.is_empty() {
+        return Ok(DynamicImage::ImageRgba8(camouflaged));
+    }
+
     // Creating graphs
-    let _graph = mount_graph(seg_f, seg_b);
+    enter(&mut progress, Stage::Graph)?;
+    let (graph, division) = mount_graph(seg_f, seg_b, params.connectivity, params.neighbours);
+
+    // Labeling foreground segments with background ones
+    enter(&mut progress, Stage::Label)?;
+    let labels =
+        alpha_beta_swap_with_progress(&graph, division, params, || progress(Stage::Label))?;
+
+    // Remapping foreground luminance onto the chosen background tones
+    enter(&mut progress, Stage::Remap)?;
+    let tone_map = ToneMap::new(&graph, &labels);
+    let remapped = remap_luminance(&full_f, &graph, &tone_map);
+
+    // Filling the foreground with background texture, guided by the remapped luminance
+    enter(&mut progress, Stage::Synthesize)?;
+    let mut mask = Segment::new();
+    for f in 0..division {
+        for (y, ranges) in graph[NodeIndex::new(f)].seg.iter() {
+            mask.entry(*y).or_default().extend(ranges.iter().cloned());
+        }
+    }
+
+    let x_0 = pos.0.clamp(0, i_b.width() as i64) as u32;
+    let y_0 = pos.1.clamp(0, i_b.height() as i64) as u32;
+    let x_1 = (pos.0 + i_f.width() as i64).clamp(0, i_b.width() as i64) as u32;
+    let y_1 = (pos.1 + i_f.height() as i64).clamp(0, i_b.height() as i64) as u32;
+
+    let source = crop_imm(&camouflaged, x_0, y_0, x_1 - x_0, y_1 - y_0).to_image();
+    let texture = TextureSynthesis::synthesize_with_progress(
+        &source,
+        &remapped,
+        &mask,
+        &SynthesisParams::default(),
+        || progress(Stage::Synthesize),
+    )
+    .ok_or(CamouflageError::Cancelled)?;
+
+    composite(&mut camouflaged, &texture);
+
+    Ok(DynamicImage::ImageRgba8(camouflaged))
+}
+
+/// Background and foreground segments, the foreground placed at `pos` over the background, along
+/// with the full resolution foreground luminance placed the same way
+fn segment_images<F>(
+    i_b: &DynamicImage,
+    i_f: &DynamicImage,
+    pos: (i64, i64),
+    params: &CamouflageParams,
+    progress: &mut F,
+) -> Result<(ImageSegments, ImageSegments, GrayAlphaImage), CamouflageError>
+where
+    F: FnMut(Stage) -> bool,
+{
+    // Converting to luminance, keeping the full resolution foreground for remapping
+    let mut lu_b = i_b.to_luma_alpha8();
+    let mut lu_f = i_f.to_luma_alpha8();
+    let full_f = lu_f.clone();
+
+    // The other modes segment the original images, so they are neither smoothed nor quantized
+    let by_tone = params.segmentation == SegmentationMode::Tone;
+
+    // Smoothing the luminance, so that gradients and noise give fewer segments
+    enter(progress, Stage::Smooth)?;
+    if let Some(prefilter) = params.prefilter.as_ref().filter(|_| by_tone) {
+        lu_b = bilateral_with_progress(&lu_b, prefilter, || progress(Stage::Smooth))
+            .ok_or(CamouflageError::Cancelled)?;
+        lu_f = bilateral_with_progress(&lu_f, prefilter, || progress(Stage::Smooth))
+            .ok_or(CamouflageError::Cancelled)?;
+    }
+
+    enter(progress, Stage::Quantize)?;
+    if by_tone {
+        let levels_b = params.background_levels.count(&lu_b);
+        let levels_f = params.foreground_levels.count(&lu_f);
+        params.background_quantizer.quantize(&mut lu_b, levels_b)?;
+        params.foreground_quantizer.quantize(&mut lu_f, levels_f)?;
+    }
+
+    // Placing the foreground over the background, so both share coordinates
+    let lu_f = place(&lu_f, (lu_b.width(), lu_b.height()), pos);
+    let full_f = place(&full_f, (lu_b.width(), lu_b.height()), pos);
+
+    // Segmenting images
+    enter(progress, Stage::Segment)?;
+    let (seg_b, seg_f) = match params.segmentation {
+        SegmentationMode::Tone => (
+            ImgSegmentation::segment_img(&lu_b, params.connectivity)?,
+            ImgSegmentation::segment_img(&lu_f, params.connectivity)?,
+        ),
+        SegmentationMode::Colour { space, threshold } => {
+            let rgb_f = place(&i_f.to_rgba8(), lu_b.dimensions(), pos);
+
+            (
+                ImgSegmentation::segment_colour_img(
+                    &i_b.to_rgba8(),
+                    space,
+                    threshold,
+                    params.connectivity,
+                )?,
+                ImgSegmentation::segment_colour_img(&rgb_f, space, threshold, params.connectivity)?,
+            )
+        }
+        SegmentationMode::Tolerance {
+            tolerance,
+            reference,
+        } => (
+            ImgSegmentation::segment_img_tolerance(
+                &i_b.to_luma_alpha8(),
+                tolerance,
+                reference,
+                params.connectivity,
+            )?,
+            ImgSegmentation::segment_img_tolerance(
+                &full_f,
+                tolerance,
+                reference,
+                params.connectivity,
+            )?,
+        ),
+        SegmentationMode::Superpixels { size, compactness } => {
+            let rgb_f = place(&i_f.to_rgba8(), lu_b.dimensions(), pos);
+            let slic = SlicParams {
+                size,
+                compactness,
+                connectivity: params.connectivity,
+                ..SlicParams::default()
+            };
+
+            (
+                Slic::segment(&i_b.to_rgba8(), slic)?,
+                Slic::segment(&rgb_f, slic)?,
+            )
+        }
+    };
+
+    // Merging the smallest segments into their neighbours
+    let seg_b = merge_small(seg_b, &lu_b, params.min_area, params.connectivity);
+    let seg_f = merge_small(seg_f, &lu_f, params.min_area, params.connectivity);
+
+    Ok((seg_b, seg_f, full_f))
+}
+
+/// Calls `progress` at the start of a stage, a false return cancelling the pipeline
+#[inline]
+fn enter<F>(progress: &mut F, stage: Stage) -> Result<(), CamouflageError>
+where
+    F: FnMut(Stage) -> bool,
+{
+    progress(stage)
+        .then_some(())
+        .ok_or(CamouflageError::Cancelled)
+}
+
+/// Transparent image of the given dimensions, with `img` copied at `pos`
+fn place<P>(
+    img: &ImageBuffer<P, Vec<P::Subpixel>>,
+    dimensions: (u32, u32),
+    pos: (i64, i64),
+) -> ImageBuffer<P, Vec<P::Subpixel>>
+where
+    P: Pixel,
+{
+    let mut res = ImageBuffer::new(dimensions.0, dimensions.1);
+    replace(&mut res, img, pos.0, pos.1);
+
+    res
+}
+
+/// Blends a layer over the image, using the layer's alpha
+fn composite(img: &mut RgbaImage, layer: &RgbaImage) {
+    for (x, y, pixel) in layer.enumerate_pixels() {
+        if x >= img.width() || y >= img.height() || pixel[3] == 0 {
+            continue;
+        }
+
+        let alpha = pixel[3] as f64 / u8::MAX as f64;
+        let under = img.get_pixel(x, y).0;
+        let mut blended = [0; 4];
+
+        for c in 0..3 {
+            blended[c] = (pixel[c] as f64 * alpha + under[c] as f64 * (1.0 - alpha)).round() as u8;
+        }
+        blended[3] = under[3].max(pixel[3]);
+
+        img.put_pixel(x, y, Rgba(blended));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, Rgba, RgbaImage};
+
+    use crate::{
+        colour::ColourSpace,
+        helpers::Area,
+        quantization::{smoothing::BilateralParams, Levels, Quantizer},
+        segmentation::criterion::SegmentationMode,
+    };
+
+    use super::{
+        camouflage_img, camouflage_img_with_progress, segment_images, CamouflageError,
+        CamouflageParams, Stage,
+    };
+
+    /// Horizontal stripes of two tones
+    fn stripes(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(width, height, |_, y| {
+            if y / 4 % 2 == 0 {
+                Rgba([40, 50, 60, 255])
+            } else {
+                Rgba([190, 180, 170, 255])
+            }
+        }))
+    }
+
+    /// Stripes of two dark and two light tones, the middle ones covering most of the image
+    fn four_tones(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(width, height, |_, y| {
+            let tone = match y % 20 {
+                0..=1 => 0,
+                2..=9 => 90,
+                10..=17 => 110,
+                _ => 200,
+            };
+            Rgba([tone, tone, tone, 255])
+        }))
+    }
+
+    #[test]
+    fn quantizer_changes_segments() {
+        let areas = |quantizer| {
+            let params = CamouflageParams {
+                background_quantizer: quantizer,
+                foreground_quantizer: quantizer,
+                background_levels: Levels::Fixed(2),
+                foreground_levels: Levels::Fixed(2),
+                ..CamouflageParams::default()
+            };
+            let (i_b, i_f) = (four_tones(60, 60), four_tones(40, 40));
+
+            let (_, seg_f, _) =
+                segment_images(&i_b, &i_f, (10, 10), &params, &mut |_| true).unwrap();
+            seg_f
+                .iter()
+                .map(|segment| segment.seg.area())
+                .collect::<Vec<usize>>()
+        };
+
+        // Uniform bins split the middle tones, while Otsu sets the extreme stripes apart
+        let uniform = areas(Quantizer::Uniform);
+        assert_eq!(uniform[..2], [400, 400]);
+        let otsu = areas(Quantizer::Otsu);
+        assert_ne!(uniform, otsu);
+    }
+
+    #[test]
+    fn smoothing_by_tone() {
+        let segments = |prefilter, segmentation| {
+            let params = CamouflageParams {
+                prefilter,
+                segmentation,
+                ..CamouflageParams::default()
+            };
+            let (i_b, i_f) = (four_tones(60, 60), four_tones(40, 40));
+
+            let (seg_b, _, _) =
+                segment_images(&i_b, &i_f, (10, 10), &params, &mut |_| true).unwrap();
+            seg_b
+                .iter()
+                .map(|segment| (segment.tone, segment.seg.area()))
+                .collect::<Vec<(u8, usize)>>()
+        };
+        let smooth = BilateralParams {
+            spatial_sigma: 4.0,
+            range_sigma: 40.0,
+        };
+
+        // Smoothing blends the middle stripes when segmenting by tone, and is left out otherwise
+        let tone = SegmentationMode::Tone;
+        assert_ne!(segments(None, tone), segments(Some(smooth), tone));
+
+        let colour = SegmentationMode::Colour {
+            space: ColourSpace::Rgb,
+            threshold: 10.0,
+        };
+        assert_eq!(segments(None, colour), segments(Some(smooth), colour));
+    }
+
+    #[test]
+    fn cancelled_smoothing() {
+        let params = CamouflageParams {
+            prefilter: Some(BilateralParams::default()),
+            ..CamouflageParams::default()
+        };
+
+        // Stopped within the first rows of the background
+        let mut calls = 0;
+        let res = camouflage_img_with_progress(
+            &four_tones(60, 60),
+            &four_tones(40, 40),
+            (10, 10),
+            &params,
+            |stage| {
+                assert_eq!(stage, Stage::Smooth);
+                calls += 1;
+                calls < 5
+            },
+        );
+
+        assert!(matches!(res, Err(CamouflageError::Cancelled)));
+        assert_eq!(calls, 5);
+    }
+
+    #[test]
+    fn every_stage() {
+        let mut stages = Vec::new();
+        camouflage_img_with_progress(
+            &four_tones(60, 60),
+            &four_tones(40, 40),
+            (10, 10),
+            &CamouflageParams::default(),
+            |stage| {
+                if stages.last() != Some(&stage) {
+                    stages.push(stage);
+                }
+                true
+            },
+        )
+        .unwrap();
+
+        // Even without smoothing, the progress goes through the whole pipeline
+        assert_eq!(stages, Stage::ALL);
+        assert_eq!(stages[0].progress(), 0.0);
+    }
+
+    #[test]
+    fn no_neighbours() {
+        let params = CamouflageParams {
+            neighbours: 0,
+            ..CamouflageParams::default()
+        };
+
+        let res = camouflage_img(&stripes(24, 24), &stripes(8, 8), (4, 4), &params);
+        assert!(matches!(res, Err(CamouflageError::NoNeighbours)));
 
-    camouflaged
+        let params = CamouflageParams {
+            neighbours: 1,
+            ..CamouflageParams::default()
+        };
+        assert!(camouflage_img(&stripes(24, 24), &stripes(8, 8), (4, 4), &params).is_ok());
+    }
 }
@@ -0,0 +1,429 @@
+use std::collections::HashSet;
+
+use petgraph::{graph::NodeIndex, visit::EdgeRef};
+
+use crate::{
+    graphs::{maxflow::FlowGraph, EdgeKind, SegmentGraph},
+    helpers::normalize_tone,
+};
+
+use super::{CamouflageError, CamouflageParams};
+
+/// Index -> foreground node
+/// Value -> background node whose tone the foreground segment takes
+pub type Labeling = Vec<NodeIndex>;
+
+/// Cost of a label that is not among the K nearest background segments
+const FORBIDDEN_COST: f64 = 1e6;
+const MAX_ITERATIONS: usize = 16;
+const EPSILON: f64 = 1e-9;
+
+struct LabelingProblem<'a> {
+    graph: &'a SegmentGraph,
+    params: &'a CamouflageParams,
+    /// Background nodes each foreground node may take
+    candidates: Vec<Vec<NodeIndex>>,
+    /// Foreground nodes sharing a boundary with each foreground node
+    boundaries: Vec<Vec<usize>>,
+}
+
+impl<'a> LabelingProblem<'a> {
+    fn new(graph: &'a SegmentGraph, division: usize, params: &'a CamouflageParams) -> Self {
+        let mut candidates = vec![Vec::new(); division];
+        let mut boundaries = vec![Vec::new(); division];
+
+        for f in 0..division {
+            for edge in graph.edges(NodeIndex::new(f)) {
+                match edge.weight().kind {
+                    EdgeKind::Boundary => boundaries[f].push(edge.target().index()),
+                    EdgeKind::Candidate => candidates[f].push(edge.target()),
+                }
+            }
+        }
+
+        Self {
+            graph,
+            params,
+            candidates,
+            boundaries,
+        }
+    }
+
+    #[inline]
+    fn tone(&self, node: NodeIndex) -> f64 {
+        normalize_tone(self.graph[node].tone)
+    }
+
+    /// Standout term: how far the chosen background tone is from the foreground one
+    fn data_cost(&self, f: usize, label: NodeIndex) -> f64 {
+        if !self.candidates[f].contains(&label) {
+            return FORBIDDEN_COST;
+        }
+
+        self.params.standout * (self.tone(NodeIndex::new(f)) - self.tone(label)).abs()
+    }
+
+    /// Immediacy term: keeps the contrast between neighbouring foreground segments
+    fn smoothness_cost(&self, f: usize, g: usize, l_f: NodeIndex, l_g: NodeIndex) -> f64 {
+        let original = self.tone(NodeIndex::new(f)) - self.tone(NodeIndex::new(g));
+        let labeled = self.tone(l_f) - self.tone(l_g);
+
+        let potts = if l_f == l_g {
+            0.0
+        } else {
+            self.params.smoothness
+        };
+
+        self.params.immediacy * (labeled - original).abs() + potts
+    }
+
+    /// Energy of the terms that involve at least one node of `nodes`
+    fn local_energy(&self, labels: &Labeling, nodes: &[usize], in_set: &[bool]) -> f64 {
+        nodes.iter().fold(0.0, |acc, &f| {
+            let pairwise = self.boundaries[f]
+                .iter()
+                // Edges inside the set are counted only once
+                .filter(|&&g| !in_set[g] || f < g)
+                .fold(0.0, |acc, &g| {
+                    acc + self.smoothness_cost(f, g, labels[f], labels[g])
+                });
+
+            acc + self.data_cost(f, labels[f]) + pairwise
+        })
+    }
+
+    fn initial_labeling(&self) -> Labeling {
+        (0..self.candidates.len())
+            .map(|f| {
+                self.candidates[f]
+                    .iter()
+                    .copied()
+                    .min_by(|a, b| self.data_cost(f, *a).total_cmp(&self.data_cost(f, *b)))
+                    .expect("every foreground segment needs a background candidate")
+            })
+            .collect()
+    }
+
+    /// Label pairs for which a swap can change something
+    fn swap_pairs(&self, labels: &Labeling) -> Vec<(NodeIndex, NodeIndex)> {
+        let mut pairs = HashSet::new();
+
+        for (f, candidates) in self.candidates.iter().enumerate() {
+            for &candidate in candidates.iter().filter(|c| **c != labels[f]) {
+                pairs.insert((labels[f].min(candidate), labels[f].max(candidate)));
+            }
+        }
+
+        let mut pairs = Vec::from_iter(pairs);
+        pairs.sort();
+        pairs
+    }
+
+    /// Solves the binary problem of every node labeled `alpha` or `beta` choosing between the two.
+    /// Returns whether the energy decreased.
+    fn swap(&self, labels: &mut Labeling, alpha: NodeIndex, beta: NodeIndex) -> bool {
+        let nodes: Vec<usize> = (0..labels.len())
+            .filter(|f| labels[*f] == alpha || labels[*f] == beta)
+            .collect();
+
+        let mut in_set = vec![false; labels.len()];
+        let mut position = vec![0; labels.len()];
+        for (i, &f) in nodes.iter().enumerate() {
+            in_set[f] = true;
+            position[f] = i;
+        }
+
+        // Alpha stays with the source, beta goes to the sink
+        let mut flow_graph = FlowGraph::new(nodes.len());
+
+        for (i, &f) in nodes.iter().enumerate() {
+            let mut cost_alpha = self.data_cost(f, alpha);
+            let mut cost_beta = self.data_cost(f, beta);
+
+            for &g in self.boundaries[f].iter() {
+                if !in_set[g] {
+                    cost_alpha += self.smoothness_cost(f, g, alpha, labels[g]);
+                    cost_beta += self.smoothness_cost(f, g, beta, labels[g]);
+                } else if f < g {
+                    let j = position[g];
+                    let e_aa = self.smoothness_cost(f, g, alpha, alpha);
+                    let e_ab = self.smoothness_cost(f, g, alpha, beta);
+                    let e_ba = self.smoothness_cost(f, g, beta, alpha);
+                    let e_bb = self.smoothness_cost(f, g, beta, beta);
+
+                    // E(x_i, x_j) = e_aa + (e_ba - e_aa) x_i + (e_bb - e_ba) x_j + w (1 - x_i) x_j
+                    add_unary(&mut flow_graph, i, e_ba - e_aa);
+                    add_unary(&mut flow_graph, j, e_bb - e_ba);
+                    flow_graph.add_edge(i, j, (e_ab + e_ba - e_aa - e_bb).max(0.0), 0.0);
+                }
+            }
+
+            flow_graph.add_terminal_weights(i, cost_beta, cost_alpha);
+        }
+
+        let source_side = flow_graph.max_flow().source_side;
+
+        let before = self.local_energy(labels, &nodes, &in_set);
+        let mut swapped = labels.clone();
+        for (i, &f) in nodes.iter().enumerate() {
+            swapped[f] = if source_side[i] { alpha } else { beta };
+        }
+        let after = self.local_energy(&swapped, &nodes, &in_set);
+
+        if after < before - EPSILON {
+            *labels = swapped;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Adds `cost * x`, where x is 1 for beta, keeping both terminal capacities non-negative
+#[inline]
+fn add_unary(flow_graph: &mut FlowGraph, node: usize, cost: f64) {
+    if cost > 0.0 {
+        flow_graph.add_terminal_weights(node, cost, 0.0);
+    } else {
+        flow_graph.add_terminal_weights(node, 0.0, -cost);
+    }
+}
+
+/// Chooses, for each foreground node of the graph (`0..division`), one of its candidate background
+/// segments by alpha-beta swap moves until the energy stops decreasing.
+pub fn alpha_beta_swap(
+    graph: &SegmentGraph,
+    division: usize,
+    params: &CamouflageParams,
+) -> Labeling {
+    alpha_beta_swap_with_progress(graph, division, params, || true)
+        .expect("labeling without a progress callback is never cancelled")
+}
+
+/// Same as [`alpha_beta_swap`], calling `progress` before each swap move.
+/// Stops with [`CamouflageError::Cancelled`] as soon as `progress` returns false.
+pub fn alpha_beta_swap_with_progress<F>(
+    graph: &SegmentGraph,
+    division: usize,
+    params: &CamouflageParams,
+    mut progress: F,
+) -> Result<Labeling, CamouflageError>
+where
+    F: FnMut() -> bool,
+{
+    let problem = LabelingProblem::new(graph, division, params);
+    let mut labels = problem.initial_labeling();
+
+    for _ in 0..MAX_ITERATIONS {
+        let mut improved = false;
+
+        for (alpha, beta) in problem.swap_pairs(&labels) {
+            if !progress() {
+                return Err(CamouflageError::Cancelled);
+            }
+
+            improved |= problem.swap(&mut labels, alpha, beta);
+        }
+
+        if !improved {
+            break;
+        }
+    }
+
+    Ok(labels)
+}
+
+#[cfg(test)]
+mod tests {
+    use petgraph::graph::NodeIndex;
+
+    use image::{GrayAlphaImage, LumaA};
+
+    use crate::{
+        camouflage::{
+            remapping::{remap_luminance, ToneMap},
+            CamouflageError, CamouflageParams,
+        },
+        graphs::{SegmentEdge, SegmentGraph},
+        segmentation::GeoSegment,
+    };
+
+    use super::{alpha_beta_swap, alpha_beta_swap_with_progress, LabelingProblem};
+
+    fn segment(tone: u8) -> GeoSegment {
+        GeoSegment {
+            tone,
+            ..GeoSegment::default()
+        }
+    }
+
+    /// Single pixel segment at `x` on the first row
+    fn pixel(tone: u8, x: u32) -> GeoSegment {
+        GeoSegment {
+            tone,
+            seg: [(0, vec![x..=x])].into(),
+            ..GeoSegment::default()
+        }
+    }
+
+    /// Two neighbouring foreground segments, each able to take any of three background tones
+    fn two_segments() -> SegmentGraph {
+        let mut graph = SegmentGraph::new_undirected();
+
+        let f_dark = graph.add_node(segment(110));
+        let f_light = graph.add_node(segment(130));
+        graph.add_edge(
+            f_dark,
+            f_light,
+            SegmentEdge::boundary(&graph[f_dark], &graph[f_light], 1),
+        );
+
+        let b_mid = graph.add_node(segment(120));
+        let b_dark = graph.add_node(segment(98));
+        let b_light = graph.add_node(segment(142));
+
+        for f_node in [f_dark, f_light] {
+            for b_node in [b_mid, b_dark, b_light] {
+                let edge = SegmentEdge::candidate(&graph[f_node], &graph[b_node]);
+                graph.add_edge(f_node, b_node, edge);
+            }
+        }
+
+        graph
+    }
+
+    /// Sum of the distances between each foreground tone and the tone of its label
+    fn tone_mismatch(graph: &SegmentGraph, labels: &[NodeIndex]) -> i32 {
+        labels.iter().enumerate().fold(0, |acc, (f, label)| {
+            acc + (graph[NodeIndex::new(f)].tone as i32 - graph[*label].tone as i32).abs()
+        })
+    }
+
+    /// Chain of three foreground segments from dark to light, where the closest background tones
+    /// of the first two are in the opposite order
+    fn inverted_chain() -> SegmentGraph {
+        let mut graph = SegmentGraph::new_undirected();
+
+        let f_nodes =
+            [(100, 0), (120, 1), (140, 2)].map(|(tone, x)| graph.add_node(pixel(tone, x)));
+        for pair in f_nodes.windows(2) {
+            let edge = SegmentEdge::boundary(&graph[pair[0]], &graph[pair[1]], 1);
+            graph.add_edge(pair[0], pair[1], edge);
+        }
+
+        for (f_node, tones) in f_nodes.into_iter().zip([[118, 60], [104, 150], [150, 170]]) {
+            for tone in tones {
+                let b_node = graph.add_node(pixel(tone, 0));
+                let edge = SegmentEdge::candidate(&graph[f_node], &graph[b_node]);
+                graph.add_edge(f_node, b_node, edge);
+            }
+        }
+
+        graph
+    }
+
+    #[test]
+    fn contrast_preserved() {
+        let graph = inverted_chain();
+        let params = CamouflageParams::default();
+        let problem = LabelingProblem::new(&graph, 3, &params);
+
+        // Matching the tones alone inverts the first two segments
+        let initial = problem.initial_labeling();
+        assert!(graph[initial[0]].tone > graph[initial[1]].tone);
+
+        let labels = alpha_beta_swap(&graph, 3, &params);
+
+        for (f, label) in labels.iter().enumerate() {
+            assert!(problem.candidates[f].contains(label));
+        }
+
+        let nodes = [0, 1, 2];
+        let in_set = [true; 3];
+        assert!(
+            problem.local_energy(&labels, &nodes, &in_set)
+                < problem.local_energy(&initial, &nodes, &in_set)
+        );
+
+        // The remapped foreground keeps the strict order of its tones
+        let fg = GrayAlphaImage::from_fn(3, 1, |x, _| {
+            LumaA([graph[NodeIndex::new(x as usize)].tone, 255])
+        });
+        let remapped = remap_luminance(&fg, &graph, &ToneMap::new(&graph, &labels));
+        assert!(remapped
+            .pixels()
+            .zip(remapped.pixels().skip(1))
+            .all(|(a, b)| a[0] < b[0]));
+
+        // Which is lost when the labels are only pooled back into order
+        let pooled = remap_luminance(&fg, &graph, &ToneMap::new(&graph, &initial));
+        assert_eq!(pooled.get_pixel(0, 0), pooled.get_pixel(1, 0));
+    }
+
+    #[test]
+    fn immediacy_weight() {
+        let graph = two_segments();
+
+        let low = CamouflageParams {
+            immediacy: 0.1,
+            ..CamouflageParams::default()
+        };
+        let labels = alpha_beta_swap(&graph, 2, &low);
+
+        assert_eq!(labels[0], labels[1]);
+
+        let high = CamouflageParams {
+            immediacy: 10.0,
+            ..CamouflageParams::default()
+        };
+        let labels = alpha_beta_swap(&graph, 2, &high);
+
+        assert!(graph[labels[0]].tone < graph[labels[1]].tone);
+    }
+
+    #[test]
+    fn standout_weight() {
+        let graph = two_segments();
+
+        let default = alpha_beta_swap(&graph, 2, &CamouflageParams::default());
+        let high = CamouflageParams {
+            standout: 50.0,
+            ..CamouflageParams::default()
+        };
+        let labels = alpha_beta_swap(&graph, 2, &high);
+
+        assert!(tone_mismatch(&graph, &labels) < tone_mismatch(&graph, &default));
+    }
+
+    #[test]
+    fn smoothness_weight() {
+        let graph = two_segments();
+
+        let high = CamouflageParams {
+            smoothness: 1.0,
+            ..CamouflageParams::default()
+        };
+        let labels = alpha_beta_swap(&graph, 2, &high);
+
+        assert_eq!(labels[0], labels[1]);
+    }
+
+    #[test]
+    fn cancelled() {
+        let graph = inverted_chain();
+        let params = CamouflageParams::default();
+
+        let mut moves = 0;
+        let res = alpha_beta_swap_with_progress(&graph, 3, &params, || {
+            moves += 1;
+            moves < 2
+        });
+
+        assert!(matches!(res, Err(CamouflageError::Cancelled)));
+        assert_eq!(moves, 2);
+        assert_eq!(
+            alpha_beta_swap_with_progress(&graph, 3, &params, || true).unwrap(),
+            alpha_beta_swap(&graph, 3, &params)
+        );
+    }
+}
@@ -0,0 +1,235 @@
+use image::{GrayAlphaImage, LumaA};
+use petgraph::{graph::NodeIndex, visit::EdgeRef};
+
+use crate::{
+    graphs::{EdgeKind, SegmentGraph},
+    helpers::Area,
+};
+
+use super::labeling::Labeling;
+
+/// Maps each foreground segment onto the tone of the background segment it was labeled with.
+/// Touching segments whose targets would invert their contrast are pooled to a shared tone.
+pub struct ToneMap {
+    /// Tone and target of each foreground segment
+    points: Vec<(u8, f64)>,
+}
+
+impl ToneMap {
+    pub fn new(graph: &SegmentGraph, labels: &Labeling) -> Self {
+        let tones: Vec<u8> = (0..labels.len())
+            .map(|f| graph[NodeIndex::new(f)].tone)
+            .collect();
+
+        // Touching foreground segments, the darker one first
+        let mut pairs = Vec::new();
+        for f in 0..labels.len() {
+            for edge in graph.edges(NodeIndex::new(f)) {
+                let other = edge.target().index();
+                if edge.weight().kind == EdgeKind::Boundary && tones[f] < tones[other] {
+                    pairs.push((f, other));
+                }
+            }
+        }
+
+        // Pool adjacent violators: each segment points to the one holding its pool, which holds
+        // the area weighted sum of the targets and the area
+        let mut pools: Vec<usize> = (0..labels.len()).collect();
+        let mut sums: Vec<(f64, f64)> = labels
+            .iter()
+            .enumerate()
+            .map(|(f, label)| {
+                let area = graph[NodeIndex::new(f)].seg.area().max(1) as f64;
+                (area * graph[*label].tone as f64, area)
+            })
+            .collect();
+
+        let target = |sums: &[(f64, f64)], pool: usize| sums[pool].0 / sums[pool].1;
+
+        while let Some((dark, light)) = pairs
+            .iter()
+            .map(|&(dark, light)| (find(&pools, dark), find(&pools, light)))
+            .find(|&(dark, light)| dark != light && target(&sums, dark) > target(&sums, light))
+        {
+            pools[light] = dark;
+            sums[dark].0 += sums[light].0;
+            sums[dark].1 += sums[light].1;
+        }
+
+        Self {
+            points: tones
+                .into_iter()
+                .enumerate()
+                .map(|(f, tone)| (tone, target(&sums, find(&pools, f))))
+                .collect(),
+        }
+    }
+
+    /// Moves a pixel of the foreground segment `f` by the same offset as the segment's tone
+    pub fn remap(&self, f: usize, luma: u8) -> u8 {
+        let (tone, target) = self.points[f];
+
+        (target + (luma as f64 - tone as f64))
+            .round()
+            .clamp(0.0, u8::MAX as f64) as u8
+    }
+}
+
+/// Segment holding the pool of `f`
+fn find(pools: &[usize], mut f: usize) -> usize {
+    while pools[f] != f {
+        f = pools[f];
+    }
+
+    f
+}
+
+/// Applies the tone map to the pixels of each foreground segment in the full resolution
+/// luminance, leaving the other pixels as they are
+pub fn remap_luminance(
+    lu_f: &GrayAlphaImage,
+    graph: &SegmentGraph,
+    tone_map: &ToneMap,
+) -> GrayAlphaImage {
+    let mut res = lu_f.clone();
+
+    for f in 0..tone_map.points.len() {
+        for (y, ranges) in graph[NodeIndex::new(f)].seg.iter() {
+            for x in ranges.iter().cloned().flatten() {
+                let pixel = res.get_pixel_mut(x, *y);
+                *pixel = LumaA([tone_map.remap(f, pixel[0]), pixel[1]]);
+            }
+        }
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayAlphaImage, LumaA};
+    use petgraph::graph::NodeIndex;
+    use proptest::prelude::*;
+
+    use crate::{
+        graphs::{SegmentEdge, SegmentGraph},
+        segmentation::GeoSegment,
+    };
+
+    use super::{remap_luminance, ToneMap};
+
+    /// Segment covering `width` pixels of the first row, from `x` on
+    fn segment(tone: u8, x: u32, width: u32) -> GeoSegment {
+        GeoSegment {
+            tone,
+            seg: [(0, vec![x..=x + width - 1])].into(),
+            ..GeoSegment::default()
+        }
+    }
+
+    /// Foreground segments side by side along the first row, each touching the next one
+    fn strip(graph: &mut SegmentGraph, segments: &[(u8, u32)]) -> Vec<NodeIndex> {
+        let mut x = 0;
+        let nodes: Vec<NodeIndex> = segments
+            .iter()
+            .map(|&(tone, width)| {
+                x += width;
+                graph.add_node(segment(tone, x - width, width))
+            })
+            .collect();
+
+        for pair in nodes.windows(2) {
+            let edge = SegmentEdge::boundary(&graph[pair[0]], &graph[pair[1]], 1);
+            graph.add_edge(pair[0], pair[1], edge);
+        }
+
+        nodes
+    }
+
+    /// Row of the given tones, fully opaque
+    fn row(tones: &[u8]) -> GrayAlphaImage {
+        GrayAlphaImage::from_fn(tones.len() as u32, 1, |x, _| {
+            LumaA([tones[x as usize], 255])
+        })
+    }
+
+    fn tones(img: &GrayAlphaImage) -> Vec<u8> {
+        img.pixels().map(|pixel| pixel[0]).collect()
+    }
+
+    #[test]
+    fn labeled_tones() {
+        let mut graph = SegmentGraph::new_undirected();
+        graph.add_node(segment(100, 0, 2));
+        graph.add_node(segment(100, 3, 2));
+        let b_dark = graph.add_node(segment(40, 0, 1));
+        let b_light = graph.add_node(segment(180, 0, 1));
+
+        // Same tone, but labeled with different background segments
+        let tone_map = ToneMap::new(&graph, &vec![b_dark, b_light]);
+        let fg = row(&[98, 102, 70, 99, 103]);
+
+        // Each segment takes its own label's tone, keeping the offsets of its pixels
+        let remapped = remap_luminance(&fg, &graph, &tone_map);
+        assert_eq!(tones(&remapped), [38, 42, 70, 179, 183]);
+    }
+
+    #[test]
+    fn contrast_order() {
+        let mut graph = SegmentGraph::new_undirected();
+        strip(&mut graph, &[(50, 1), (100, 3), (150, 1), (200, 1)]);
+        let b_dark = graph.add_node(segment(40, 0, 1));
+        let b_light = graph.add_node(segment(180, 0, 1));
+
+        // 100 and 150 are swapped, which would invert their contrast
+        let labels = vec![b_dark, b_light, b_dark, b_light];
+        let fg = row(&[50, 100, 100, 100, 150, 200]);
+
+        // Pooled by area: (3 * 180 + 40) / 4
+        let remapped = remap_luminance(&fg, &graph, &ToneMap::new(&graph, &labels));
+        assert_eq!(tones(&remapped), [40, 145, 145, 145, 145, 180]);
+
+        // Segments that don't touch keep their own targets
+        let edge = graph
+            .find_edge(NodeIndex::new(1), NodeIndex::new(2))
+            .unwrap();
+        graph.remove_edge(edge);
+
+        let remapped = remap_luminance(&fg, &graph, &ToneMap::new(&graph, &labels));
+        assert_eq!(tones(&remapped), [40, 180, 180, 180, 40, 180]);
+    }
+
+    proptest! {
+        #[test]
+        fn any_labels(
+            f_segments in prop::collection::vec((any::<u8>(), 1..5u32), 1..10),
+            b_tones in prop::collection::vec(any::<u8>(), 1..6),
+            choices in prop::collection::vec(any::<prop::sample::Index>(), 10),
+        ) {
+            let mut graph = SegmentGraph::new_undirected();
+            let f_nodes = strip(&mut graph, &f_segments);
+            let b_nodes: Vec<NodeIndex> = b_tones
+                .iter()
+                .map(|tone| graph.add_node(segment(*tone, 0, 1)))
+                .collect();
+
+            let labels: Vec<NodeIndex> = choices
+                .iter()
+                .take(f_segments.len())
+                .map(|choice| *choice.get(&b_nodes))
+                .collect();
+
+            let tone_map = ToneMap::new(&graph, &labels);
+            let mapped: Vec<u8> = f_nodes
+                .iter()
+                .map(|f| tone_map.remap(f.index(), graph[*f].tone))
+                .collect();
+
+            // Touching segments never swap their order
+            for (pair, mapped) in f_segments.windows(2).zip(mapped.windows(2)) {
+                prop_assert!(pair[0].0 >= pair[1].0 || mapped[0] <= mapped[1]);
+                prop_assert!(pair[0].0 <= pair[1].0 || mapped[0] >= mapped[1]);
+            }
+        }
+    }
+}
@@ -0,0 +1,635 @@
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use clap::{builder::RangedU64ValueParser, Args, Parser, Subcommand, ValueEnum};
+use image::{
+    imageops::{resize, FilterType},
+    io::Reader,
+    DynamicImage, ImageFormat,
+};
+
+use camouflage_images::{
+    camouflage::{camouflage_img_with_progress, CamouflageParams},
+    colour::ColourSpace,
+    quantization::{
+        palette::{ColourQuantizer, Palette},
+        smoothing::BilateralParams,
+        Levels, Quantizer,
+    },
+    segmentation::{
+        criterion::{Reference, SegmentationMode},
+        label_map::{colorize, save_label_map},
+        slic::SlicParams,
+        Connectivity, ImgSegmentation,
+    },
+};
+
+/// Without a subcommand, the graphical interface is opened
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Hides foregrounds in a background without opening the interface
+    Camouflage(CamouflageArgs),
+    /// Reduces an image to a few colours, printing them and saving them as swatches
+    Palette(PaletteArgs),
+    /// Segments an image by tone, saving its label map so it can be shown in the interface
+    Segment(SegmentArgs),
+}
+
+#[derive(Args)]
+pub struct CamouflageArgs {
+    #[arg(short, long)]
+    pub background: PathBuf,
+    /// Image, or directory whose images are all camouflaged in the same background
+    #[arg(short, long)]
+    pub foreground: PathBuf,
+    /// File, or directory when the foreground is a directory
+    #[arg(short, long)]
+    pub output: PathBuf,
+    /// Column of the background where the foreground's left side goes
+    #[arg(short, default_value_t = 0, allow_hyphen_values = true)]
+    pub x: i64,
+    /// Row of the background where the foreground's top goes
+    #[arg(short, default_value_t = 0, allow_hyphen_values = true)]
+    pub y: i64,
+    /// Resizing factor applied to the foreground before placing it
+    #[arg(short, long, default_value_t = 1.0, value_parser = positive)]
+    pub scale: f64,
+    /// Smooth the luminance while keeping its edges before quantizing it, only when segmenting by
+    /// tone
+    #[arg(long, conflicts_with_all = ["colour", "tolerance", "superpixels"])]
+    pub smooth: bool,
+    /// Spread of the smoothing around each pixel, in pixels
+    #[arg(long, requires = "smooth", value_parser = positive)]
+    pub spatial_sigma: Option<f64>,
+    /// Spread of the tones smoothed together, lower values keeping weaker edges
+    #[arg(long, requires = "smooth", value_parser = positive)]
+    pub range_sigma: Option<f64>,
+    /// How the tones of the background are grouped before segmenting it
+    #[arg(long, value_enum, default_value_t = Method::Uniform)]
+    pub background_quantizer: Method,
+    /// How the tones of the foreground are grouped before segmenting it
+    #[arg(long, value_enum, default_value_t = Method::Uniform)]
+    pub foreground_quantizer: Method,
+    /// Tones the background is quantized to, chosen from its histogram when left out
+    #[arg(long)]
+    pub background_levels: Option<usize>,
+    /// Tones the foreground is quantized to, chosen from its histogram when left out
+    #[arg(long)]
+    pub foreground_levels: Option<usize>,
+    #[arg(long)]
+    pub immediacy: Option<f64>,
+    #[arg(long)]
+    pub standout: Option<f64>,
+    #[arg(long)]
+    pub smoothness: Option<f64>,
+    /// Closest background segments each foreground segment may take
+    #[arg(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub neighbours: Option<usize>,
+    /// Segment by colour, joining pixels within this distance of their segment's first one
+    #[arg(long)]
+    pub colour: Option<f64>,
+    /// Measure colour distances in CIELAB instead of RGB
+    #[arg(long, requires = "colour")]
+    pub lab: bool,
+    /// Segment the unquantized luminance, joining pixels within this many tones of their
+    /// segment's first one
+    #[arg(long, conflicts_with = "colour")]
+    pub tolerance: Option<u8>,
+    /// Compare with the mean tone of the segment instead of its first pixel
+    #[arg(long, requires = "tolerance")]
+    pub mean: bool,
+    /// Segment into SLIC superpixels starting from square cells with sides of this many pixels
+    #[arg(long, conflicts_with_all = ["colour", "tolerance"])]
+    pub superpixels: Option<u32>,
+    /// Regularity of the superpixels, against how closely they follow colour edges
+    #[arg(long, requires = "superpixels", default_value_t = SlicParams::default().compactness)]
+    pub compactness: f64,
+    /// Merge the segments with fewer pixels than this into a touching one
+    #[arg(long)]
+    pub min_area: Option<usize>,
+    /// Connect pixels and segments through their corners too
+    #[arg(long)]
+    pub eight_connected: bool,
+}
+
+#[derive(Args)]
+pub struct PaletteArgs {
+    #[arg(short, long)]
+    pub input: PathBuf,
+    /// Most colours in the palette
+    #[arg(short = 'n', long, default_value_t = 8)]
+    pub colours: usize,
+    /// Split the colours by median cut only, without refining them with k-means
+    #[arg(long)]
+    pub median_cut: bool,
+    /// Image with one square for each colour
+    #[arg(short, long)]
+    pub swatches: Option<PathBuf>,
+    /// The input with its colours reduced to the palette
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct SegmentArgs {
+    #[arg(short, long)]
+    pub input: PathBuf,
+    /// Label map of the segments, stored losslessly as a PNG
+    #[arg(short, long)]
+    pub output: PathBuf,
+    /// How the tones are grouped before segmenting
+    #[arg(long, value_enum, default_value_t = Method::Uniform)]
+    pub quantizer: Method,
+    /// Tones the image is quantized to, chosen from its histogram when left out
+    #[arg(long)]
+    pub levels: Option<usize>,
+    /// Image with a distinct colour for each segment
+    #[arg(short, long)]
+    pub colours: Option<PathBuf>,
+    /// Connect pixels through their corners too
+    #[arg(long)]
+    pub eight_connected: bool,
+}
+
+/// Command line names of the [`Quantizer`]s
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Method {
+    /// Evenly spaced levels between the darkest and the lightest tones
+    Uniform,
+    /// Multi-level Otsu thresholds
+    Otsu,
+    /// K-means on the histogram
+    KMeans,
+    /// Lloyd-Max on the histogram
+    LloydMax,
+}
+
+impl From<Method> for Quantizer {
+    fn from(method: Method) -> Self {
+        match method {
+            Method::Uniform => Quantizer::Uniform,
+            Method::Otsu => Quantizer::Otsu,
+            Method::KMeans => Quantizer::KMeans,
+            Method::LloydMax => Quantizer::LloydMax,
+        }
+    }
+}
+
+impl CamouflageArgs {
+    fn params(&self) -> CamouflageParams {
+        let default = CamouflageParams::default();
+
+        CamouflageParams {
+            immediacy: self.immediacy.unwrap_or(default.immediacy),
+            standout: self.standout.unwrap_or(default.standout),
+            smoothness: self.smoothness.unwrap_or(default.smoothness),
+            prefilter: self.smooth.then(|| {
+                let default = BilateralParams::default();
+
+                BilateralParams {
+                    spatial_sigma: self.spatial_sigma.unwrap_or(default.spatial_sigma),
+                    range_sigma: self.range_sigma.unwrap_or(default.range_sigma),
+                }
+            }),
+            background_quantizer: self.background_quantizer.into(),
+            foreground_quantizer: self.foreground_quantizer.into(),
+            background_levels: self
+                .background_levels
+                .map_or(default.background_levels, Levels::Fixed),
+            foreground_levels: self
+                .foreground_levels
+                .map_or(default.foreground_levels, Levels::Fixed),
+            connectivity: if self.eight_connected {
+                Connectivity::Eight
+            } else {
+                default.connectivity
+            },
+            segmentation: match self.colour {
+                Some(threshold) => SegmentationMode::Colour {
+                    space: if self.lab {
+                        ColourSpace::Lab
+                    } else {
+                        ColourSpace::Rgb
+                    },
+                    threshold,
+                },
+                None => match self.tolerance {
+                    Some(tolerance) => SegmentationMode::Tolerance {
+                        tolerance,
+                        reference: if self.mean {
+                            Reference::Mean
+                        } else {
+                            Reference::Seed
+                        },
+                    },
+                    None => match self.superpixels {
+                        Some(size) => SegmentationMode::Superpixels {
+                            size,
+                            compactness: self.compactness,
+                        },
+                        None => default.segmentation,
+                    },
+                },
+            },
+            min_area: self.min_area.unwrap_or(default.min_area),
+            neighbours: self.neighbours.unwrap_or(default.neighbours),
+        }
+    }
+
+    /// Pairs of foreground and output paths
+    fn jobs(&self) -> Result<Vec<(PathBuf, PathBuf)>, Box<dyn Error>> {
+        if !self.foreground.is_dir() {
+            return Ok(vec![(self.foreground.clone(), self.output.clone())]);
+        }
+
+        fs::create_dir_all(&self.output)?;
+
+        let mut foregrounds: Vec<PathBuf> = fs::read_dir(&self.foreground)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file() && ImageFormat::from_path(path).is_ok())
+            .collect();
+        foregrounds.sort();
+
+        Ok(foregrounds
+            .into_iter()
+            .map(|path| {
+                let output = self.output.join(path.file_name().unwrap());
+                (path, output)
+            })
+            .collect())
+    }
+}
+
+/// Parses a finite number above 0
+fn positive(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(value) if value.is_finite() && value > 0.0 => Ok(value),
+        Ok(_) => Err(String::from("must be a finite number above 0")),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn open(path: &Path) -> Result<DynamicImage, Box<dyn Error>> {
+    Ok(Reader::open(path)?.with_guessed_format()?.decode()?)
+}
+
+fn scale(img: DynamicImage, factor: f64) -> Result<DynamicImage, Box<dyn Error>> {
+    if factor == 1.0 {
+        return Ok(img);
+    }
+
+    let width = (img.width() as f64 * factor).round().max(1.0);
+    let height = (img.height() as f64 * factor).round().max(1.0);
+    if width > u32::MAX as f64 || height > u32::MAX as f64 {
+        return Err(format!("scaled foreground of {width}x{height} pixels is too large").into());
+    }
+
+    Ok(DynamicImage::ImageRgba8(resize(
+        &img,
+        width as u32,
+        height as u32,
+        FilterType::CatmullRom,
+    )))
+}
+
+/// In directory mode, a foreground that fails is reported and skipped, the error only being
+/// returned once the other ones are done
+pub fn run(args: CamouflageArgs) -> Result<(), Box<dyn Error>> {
+    let i_b = open(&args.background)?;
+    let params = args.params();
+    let jobs = args.jobs()?;
+    let batch = args.foreground.is_dir();
+    let mut failed = 0;
+
+    for (foreground, output) in jobs.iter() {
+        match camouflage_file(&i_b, foreground, output, &args, &params) {
+            Ok(()) => println!("{}", output.display()),
+            Err(err) if batch => {
+                eprintln!("{}: {err}", foreground.display());
+                failed += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    if failed > 0 {
+        return Err(format!("{failed} of {} foregrounds failed", jobs.len()).into());
+    }
+
+    Ok(())
+}
+
+fn camouflage_file(
+    i_b: &DynamicImage,
+    foreground: &Path,
+    output: &Path,
+    args: &CamouflageArgs,
+    params: &CamouflageParams,
+) -> Result<(), Box<dyn Error>> {
+    let i_f = scale(open(foreground)?, args.scale)?;
+
+    // The long stages report themselves several times
+    let mut current = None;
+    let camouflaged = camouflage_img_with_progress(i_b, &i_f, (args.x, args.y), params, |stage| {
+        if current.replace(stage) != Some(stage) {
+            eprintln!("{}: {}", foreground.display(), stage.name());
+        }
+        true
+    })?;
+
+    Ok(camouflaged.save(output)?)
+}
+
+pub fn run_palette(args: PaletteArgs) -> Result<(), Box<dyn Error>> {
+    let quantizer = if args.median_cut {
+        ColourQuantizer::MedianCut
+    } else {
+        ColourQuantizer::KMeans
+    };
+    let palette = Palette::new(&open(&args.input)?.to_rgba8(), args.colours, quantizer)?;
+
+    for code in palette.hex_codes() {
+        println!("{code}");
+    }
+    if let Some(path) = &args.swatches {
+        palette.swatches(32).save(path)?;
+    }
+    if let Some(path) = &args.output {
+        palette.to_rgba().save(path)?;
+    }
+
+    Ok(())
+}
+
+pub fn run_segment(args: SegmentArgs) -> Result<(), Box<dyn Error>> {
+    let mut lu = open(&args.input)?.to_luma_alpha8();
+    let levels = args.levels.map_or(Levels::Auto, Levels::Fixed).count(&lu);
+    Quantizer::from(args.quantizer).quantize(&mut lu, levels)?;
+
+    let connectivity = if args.eight_connected {
+        Connectivity::Eight
+    } else {
+        Connectivity::Four
+    };
+    let (segments, labels) = ImgSegmentation::segment_img_labeled(&lu, connectivity)?;
+
+    save_label_map(&labels, &args.output)?;
+    if let Some(path) = &args.colours {
+        colorize(&labels).save(path)?;
+    }
+    println!("{}: {} segments", args.output.display(), segments.len());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use camouflage_images::{
+        quantization::{smoothing::BilateralParams, Levels, Quantizer},
+        segmentation::label_map::open_label_map,
+    };
+    use clap::{CommandFactory, Parser};
+    use image::{DynamicImage, Rgba, RgbaImage};
+
+    use super::{run, run_segment, scale, Cli, Command};
+
+    #[test]
+    fn arguments() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::parse_from([
+            "camouflage",
+            "camouflage",
+            "-b",
+            "background.png",
+            "-f",
+            "foregrounds",
+            "-o",
+            "out",
+            "-x",
+            "-20",
+            "-y",
+            "35",
+            "--standout",
+            "3",
+            "--foreground-quantizer",
+            "k-means",
+            "--background-levels",
+            "5",
+            "--smooth",
+            "--range-sigma",
+            "12",
+        ]);
+
+        let Some(Command::Camouflage(args)) = cli.command else {
+            panic!("expected the camouflage subcommand");
+        };
+
+        assert_eq!((args.x, args.y), (-20, 35));
+        assert_eq!(args.scale, 1.0);
+        assert_eq!(args.params().standout, 3.0);
+        assert_eq!(args.params().immediacy, 2.0);
+        assert_eq!(args.params().background_quantizer, Quantizer::Uniform);
+        assert_eq!(args.params().foreground_quantizer, Quantizer::KMeans);
+        assert_eq!(args.params().background_levels, Levels::Fixed(5));
+        assert_eq!(args.params().foreground_levels, Levels::Auto);
+        assert_eq!(
+            args.params().prefilter,
+            Some(BilateralParams {
+                spatial_sigma: 3.0,
+                range_sigma: 12.0
+            })
+        );
+    }
+
+    #[test]
+    fn smoothing_spreads() {
+        let parse = |sigma| {
+            Cli::try_parse_from([
+                "camouflage",
+                "camouflage",
+                "-b",
+                "background.png",
+                "-f",
+                "foreground.png",
+                "-o",
+                "out.png",
+                "--smooth",
+                "--spatial-sigma",
+                sigma,
+            ])
+        };
+
+        for sigma in ["0", "-2", "inf", "NaN", "wide"] {
+            assert!(parse(sigma).is_err());
+        }
+        assert!(parse("0.5").is_ok());
+
+        // The other segmentation modes don't smooth
+        for mode in [
+            ["--colour", "10"],
+            ["--tolerance", "8"],
+            ["--superpixels", "16"],
+        ] {
+            let mut args = vec![
+                "camouflage",
+                "camouflage",
+                "-b",
+                "background.png",
+                "-f",
+                "foreground.png",
+                "-o",
+                "out.png",
+                "--smooth",
+            ];
+            args.extend(mode);
+            assert!(Cli::try_parse_from(args).is_err());
+        }
+    }
+
+    #[test]
+    fn scale_factor() {
+        let parse = |factor| {
+            Cli::try_parse_from([
+                "camouflage",
+                "camouflage",
+                "-b",
+                "background.png",
+                "-f",
+                "foreground.png",
+                "-o",
+                "out.png",
+                "--scale",
+                factor,
+            ])
+        };
+
+        for factor in ["0", "-0.5", "inf", "NaN"] {
+            assert!(parse(factor).is_err());
+        }
+        assert!(parse("0.25").is_ok());
+
+        let img = DynamicImage::ImageRgba8(RgbaImage::new(8, 4));
+        assert!(scale(img.clone(), 1e12).is_err());
+
+        let scaled = scale(img, 0.1).unwrap();
+        assert_eq!((scaled.width(), scaled.height()), (1, 1));
+    }
+
+    #[test]
+    fn failed_foreground() {
+        let dir = env::temp_dir().join(format!("camouflage_batch_{}", std::process::id()));
+        let (foregrounds, outputs) = (dir.join("foregrounds"), dir.join("outputs"));
+        fs::create_dir_all(&foregrounds).unwrap();
+
+        let stripes = |size| {
+            RgbaImage::from_fn(size, size, |_, y| {
+                if y / 4 % 2 == 0 {
+                    Rgba([40, 50, 60, 255])
+                } else {
+                    Rgba([190, 180, 170, 255])
+                }
+            })
+        };
+        stripes(24).save(dir.join("background.png")).unwrap();
+        fs::write(foregrounds.join("a.png"), b"not an image").unwrap();
+        stripes(8).save(foregrounds.join("b.png")).unwrap();
+
+        let cli = Cli::parse_from([
+            "camouflage".as_ref(),
+            "camouflage".as_ref(),
+            "-b".as_ref(),
+            dir.join("background.png").as_os_str(),
+            "-f".as_ref(),
+            foregrounds.as_os_str(),
+            "-o".as_ref(),
+            outputs.as_os_str(),
+        ]);
+        let Some(Command::Camouflage(args)) = cli.command else {
+            panic!("expected the camouflage subcommand");
+        };
+
+        // The broken foreground doesn't stop the one after it, but still fails the run
+        let res = run(args);
+        let done = outputs.join("b.png").is_file();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(res.unwrap_err().to_string(), "1 of 2 foregrounds failed");
+        assert!(done);
+    }
+
+    #[test]
+    fn label_map() {
+        let dir = env::temp_dir().join(format!("camouflage_labels_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let img = RgbaImage::from_fn(24, 24, |_, y| {
+            let tone = if y / 4 % 2 == 0 { 40 } else { 190 };
+            Rgba([tone, tone, tone, 255])
+        });
+        img.save(dir.join("stripes.png")).unwrap();
+
+        let cli = Cli::parse_from([
+            "camouflage".as_ref(),
+            "segment".as_ref(),
+            "-i".as_ref(),
+            dir.join("stripes.png").as_os_str(),
+            "-o".as_ref(),
+            dir.join("labels.png").as_os_str(),
+            "--levels".as_ref(),
+            "2".as_ref(),
+        ]);
+        let Some(Command::Segment(args)) = cli.command else {
+            panic!("expected the segment subcommand");
+        };
+
+        run_segment(args).unwrap();
+        let labels = open_label_map(dir.join("labels.png")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // One label for each stripe
+        assert_eq!(labels.pixels().map(|p| p[0]).max(), Some(6));
+        assert_eq!(labels.get_pixel(0, 0), labels.get_pixel(23, 3));
+        assert_ne!(labels.get_pixel(0, 3), labels.get_pixel(0, 4));
+    }
+
+    #[test]
+    fn no_neighbours() {
+        let parse = |neighbours| {
+            Cli::try_parse_from([
+                "camouflage",
+                "camouflage",
+                "-b",
+                "background.png",
+                "-f",
+                "foreground.png",
+                "-o",
+                "out.png",
+                "--neighbours",
+                neighbours,
+            ])
+        };
+
+        assert!(parse("0").is_err());
+
+        let Ok(Cli {
+            command: Some(Command::Camouflage(args)),
+        }) = parse("1")
+        else {
+            panic!("expected the camouflage subcommand");
+        };
+        assert_eq!(args.params().neighbours, 1);
+    }
+}
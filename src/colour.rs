@@ -0,0 +1,141 @@
+/// Coordinates of a colour in one of the [`ColourSpace`]s
+pub type Colour = [f64; 3];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ColourSpace {
+    /// sRGB channels, from 0 to 255
+    #[default]
+    Rgb,
+    /// CIE L*a*b* under D65, where distances follow perceived differences more closely
+    Lab,
+}
+
+// D65 white point
+const WHITE: Colour = [0.950_47, 1.0, 1.088_83];
+const EPSILON: f64 = 216.0 / 24389.0;
+const KAPPA: f64 = 24389.0 / 27.0;
+
+impl ColourSpace {
+    pub fn from_rgb(&self, rgb: [u8; 3]) -> Colour {
+        match self {
+            ColourSpace::Rgb => rgb.map(|c| c as f64),
+            ColourSpace::Lab => rgb_to_lab(rgb),
+        }
+    }
+
+    /// Rounded and clamped to the sRGB gamut
+    pub fn to_rgb(&self, colour: Colour) -> [u8; 3] {
+        let rgb = match self {
+            ColourSpace::Rgb => colour,
+            ColourSpace::Lab => lab_to_rgb(colour),
+        };
+
+        rgb.map(|c| c.round().clamp(0.0, u8::MAX as f64) as u8)
+    }
+}
+
+#[inline]
+pub fn distance(a: &Colour, b: &Colour) -> f64 {
+    a.iter()
+        .zip(b.iter())
+        .map(|(a, b)| (a - b).powi(2))
+        .sum::<f64>()
+        .sqrt()
+}
+
+#[inline]
+fn to_linear(c: u8) -> f64 {
+    let c = c as f64 / u8::MAX as f64;
+
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+#[inline]
+fn from_linear(c: f64) -> f64 {
+    let c = if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+
+    c * u8::MAX as f64
+}
+
+pub fn rgb_to_lab(rgb: [u8; 3]) -> Colour {
+    let [r, g, b] = rgb.map(to_linear);
+
+    let xyz = [
+        0.412_456_4 * r + 0.357_576_1 * g + 0.180_437_5 * b,
+        0.212_672_9 * r + 0.715_152_2 * g + 0.072_175_0 * b,
+        0.019_333_9 * r + 0.119_192_0 * g + 0.950_304_1 * b,
+    ];
+
+    let [f_x, f_y, f_z] = [0, 1, 2].map(|i| {
+        let t = xyz[i] / WHITE[i];
+
+        if t > EPSILON {
+            t.cbrt()
+        } else {
+            (KAPPA * t + 16.0) / 116.0
+        }
+    });
+
+    [116.0 * f_y - 16.0, 500.0 * (f_x - f_y), 200.0 * (f_y - f_z)]
+}
+
+/// Unclamped, colours outside of the sRGB gamut give channels outside of 0..=255
+pub fn lab_to_rgb(lab: Colour) -> Colour {
+    let f_y = (lab[0] + 16.0) / 116.0;
+    let f_x = f_y + lab[1] / 500.0;
+    let f_z = f_y - lab[2] / 200.0;
+
+    let [x, y, z] = [f_x, f_y, f_z].map(|f| {
+        if f.powi(3) > EPSILON {
+            f.powi(3)
+        } else {
+            (116.0 * f - 16.0) / KAPPA
+        }
+    });
+    let (x, y, z) = (x * WHITE[0], y * WHITE[1], z * WHITE[2]);
+
+    [
+        3.240_454_2 * x - 1.537_138_5 * y - 0.498_531_4 * z,
+        -0.969_266_0 * x + 1.876_010_8 * y + 0.041_556_0 * z,
+        0.055_643_4 * x - 0.204_025_9 * y + 1.057_225_2 * z,
+    ]
+    .map(from_linear)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{distance, rgb_to_lab, ColourSpace};
+
+    #[test]
+    fn lab_references() {
+        let white = rgb_to_lab([255, 255, 255]);
+        let red = rgb_to_lab([255, 0, 0]);
+
+        assert!(distance(&white, &[100.0, 0.0, 0.0]) < 0.01);
+        assert!(distance(&rgb_to_lab([0, 0, 0]), &[0.0, 0.0, 0.0]) < 0.01);
+        assert!(distance(&red, &[53.24, 80.09, 67.20]) < 0.05);
+    }
+
+    #[test]
+    fn round_trip() {
+        for rgb in [
+            [0, 0, 0],
+            [255, 0, 0],
+            [12, 200, 90],
+            [128, 128, 128],
+            [250, 251, 3],
+        ] {
+            for space in [ColourSpace::Rgb, ColourSpace::Lab] {
+                assert_eq!(space.to_rgb(space.from_rgb(rgb)), rgb);
+            }
+        }
+    }
+}
@@ -1,11 +1,19 @@
-use ordered_float::OrderedFloat;
-use petgraph::prelude::UnGraph;
+pub mod kdtree;
+pub mod maxflow;
+
+use petgraph::{graph::NodeIndex, prelude::UnGraph};
+
+use self::kdtree::KdTree;
 
 use crate::{
-    helpers::{Connected, CoordinatesF},
-    segmentation::{GeoSegment, ImageSegments},
+    helpers::CoordinatesF,
+    segmentation::{
+        label_map::{adjacency, extent, to_label_map},
+        Connectivity, GeoSegment, ImageSegments,
+    },
 };
 
+/// Distance between segment centroids
 pub trait EuclideanDistance {
     fn calc_euclidean_distance(&self, other: &Self) -> f64 {
         let centr_s = self.calc_centroid();
@@ -26,16 +34,59 @@ impl EuclideanDistance for GeoSegment {
     }
 }
 
-pub type SegmentGraph = UnGraph<GeoSegment, ()>;
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EdgeKind {
+    /// Foreground segments that touch each other
+    Boundary,
+    /// Foreground segment and one of its nearest background segments
+    Candidate,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SegmentEdge {
+    pub kind: EdgeKind,
+    /// Euclidean distance between the centroids
+    pub distance: f64,
+    /// Pairs of neighbouring pixels split between the segments, 0 for candidates
+    pub boundary_length: usize,
+    pub tone_difference: u8,
+}
+
+impl SegmentEdge {
+    pub fn boundary(segment: &GeoSegment, other: &GeoSegment, boundary_length: usize) -> Self {
+        Self {
+            kind: EdgeKind::Boundary,
+            boundary_length,
+            ..Self::candidate(segment, other)
+        }
+    }
+
+    pub fn candidate(segment: &GeoSegment, other: &GeoSegment) -> Self {
+        Self {
+            kind: EdgeKind::Candidate,
+            distance: segment.calc_euclidean_distance(other),
+            boundary_length: 0,
+            tone_difference: segment.tone.abs_diff(other.tone),
+        }
+    }
+}
+
+pub type SegmentGraph = UnGraph<GeoSegment, SegmentEdge>;
 
-pub fn mount_graph(f_segments: ImageSegments, b_segments: ImageSegments) -> SegmentGraph {
+/// Returns the graph along with the index of its first background node
+pub fn mount_graph(
+    f_segments: ImageSegments,
+    b_segments: ImageSegments,
+    connectivity: Connectivity,
+    neighbours: usize,
+) -> (SegmentGraph, usize) {
     let mut res = SegmentGraph::new_undirected();
 
     for segment in f_segments.into_iter() {
         res.add_node(segment);
     }
 
-    connect_boundaries(&mut res);
+    connect_boundaries(&mut res, connectivity);
 
     let mut b_segments = b_segments.into_iter();
     let division = res.add_node(b_segments.next().unwrap());
@@ -44,73 +95,149 @@ pub fn mount_graph(f_segments: ImageSegments, b_segments: ImageSegments) -> Segm
         res.add_node(segment);
     }
 
-    connect_neighbours(&mut res, division.index());
+    connect_neighbours(&mut res, division.index(), neighbours);
 
-    res
+    (res, division.index())
 }
 
-fn connect_boundaries(seg_graph: &mut SegmentGraph) {
-    let mut nodes = seg_graph.node_indices();
+/// Links the touching segments found by scanning their label map once
+fn connect_boundaries(seg_graph: &mut SegmentGraph, connectivity: Connectivity) {
+    let dimensions = extent(seg_graph.node_weights());
+    let labels = to_label_map(seg_graph.node_weights(), dimensions);
 
-    while let Some(node) = nodes.next() {
-        let remaning_nodes = nodes.clone();
+    for ((label, other_label), boundary_length) in adjacency(&labels, connectivity) {
+        let node = NodeIndex::new(label as usize - 1);
+        let other_node = NodeIndex::new(other_label as usize - 1);
 
-        for other_node in remaning_nodes {
-            if seg_graph[node].seg.is_connected(&seg_graph[other_node].seg) {
-                seg_graph.add_edge(node, other_node, ());
-            }
-        }
+        let edge = SegmentEdge::boundary(&seg_graph[node], &seg_graph[other_node], boundary_length);
+        seg_graph.add_edge(node, other_node, edge);
     }
 }
 
-const K_VALUE: usize = 6;
-fn connect_neighbours(seg_graph: &mut SegmentGraph, division: usize) {
-    let background_nodes = seg_graph.node_indices().skip(division);
-    let total_background_nodes = seg_graph.node_count() - division;
+/// Links each foreground segment to its `neighbours` closest background segments
+fn connect_neighbours(seg_graph: &mut SegmentGraph, division: usize, neighbours: usize) {
+    let tree = KdTree::new(
+        seg_graph
+            .node_indices()
+            .skip(division)
+            .map(|b_node| (seg_graph[b_node].centroid, b_node.index())),
+    );
 
     for f_node in seg_graph.node_indices().take(division) {
-        let mut min_tree = Vec::with_capacity(total_background_nodes);
-
-        for b_node in background_nodes.clone() {
-            min_tree.push((
-                OrderedFloat(seg_graph[b_node].calc_euclidean_distance(&seg_graph[f_node])),
-                b_node,
-            ));
-        }
-
-        min_tree.sort_by_key(|(distance, _)| *distance);
+        for (_, b_node) in tree.nearest(seg_graph[f_node].centroid, neighbours) {
+            let b_node = NodeIndex::new(b_node);
 
-        for (_, b_node) in min_tree.into_iter().take(K_VALUE) {
-            seg_graph.add_edge(f_node, b_node, ());
+            let edge = SegmentEdge::candidate(&seg_graph[f_node], &seg_graph[b_node]);
+            seg_graph.add_edge(f_node, b_node, edge);
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::path::Path;
+
     use image::io::Reader;
+    use petgraph::visit::EdgeRef;
 
-    use crate::{graphs::EuclideanDistance, helpers::img_to_segs, segmentation::ImgSegmentation};
+    use crate::{
+        graphs::EuclideanDistance,
+        helpers::{img_to_segs, Boundary, Connected},
+        segmentation::{Connectivity, GeoSegment, ImgSegmentation, Segment},
+    };
 
-    use super::{connect_boundaries, connect_neighbours, mount_graph, SegmentGraph};
+    use super::{connect_boundaries, connect_neighbours, mount_graph, EdgeKind, SegmentGraph};
 
     #[test]
     fn boundaries() {
         let mut graph = SegmentGraph::new_undirected();
-        let segs = img_to_segs(r"img_segments\graph_1.png");
+        let segs = img_to_segs(Path::new("img_segments").join("graph_1.png"));
 
         for segment in segs.into_iter() {
             graph.add_node(segment);
         }
 
-        connect_boundaries(&mut graph);
+        connect_boundaries(&mut graph, Connectivity::Four);
 
         assert_eq!(graph.edge_count(), 5);
+        assert!(graph
+            .edge_weights()
+            .all(|edge| edge.kind == EdgeKind::Boundary && edge.boundary_length > 0));
+    }
+
+    #[test]
+    fn interior_boundaries() {
+        let mut graph = SegmentGraph::new_undirected();
+        // A cup, open on its bottom
+        graph.add_node(GeoSegment {
+            seg: Segment::from([
+                (0, vec![0..=6]),
+                (1, vec![0..=0, 6..=6]),
+                (2, vec![0..=0, 6..=6]),
+                (3, vec![0..=0, 6..=6]),
+            ]),
+            ..GeoSegment::default()
+        });
+        // Inside the cup, only touching the row over it
+        graph.add_node(GeoSegment {
+            seg: Segment::from([(1, vec![2..=4]), (2, vec![3..=3])]),
+            ..GeoSegment::default()
+        });
+
+        connect_boundaries(&mut graph, Connectivity::Four);
+
+        assert_eq!(graph.edge_count(), 1);
+        assert_eq!(graph.edge_weights().next().unwrap().boundary_length, 3);
+    }
+
+    #[test]
+    fn matches_pairwise() {
+        for connectivity in [Connectivity::Four, Connectivity::Eight] {
+            let mut graph = SegmentGraph::new_undirected();
+            for segment in img_to_segs(Path::new("img_segments").join("graph_1.png")) {
+                graph.add_node(segment);
+            }
+
+            connect_boundaries(&mut graph, connectivity);
+
+            for node in graph.node_indices() {
+                for other_node in graph.node_indices().filter(|other| *other != node) {
+                    let (segment, other) = (&graph[node].seg, &graph[other_node].seg);
+                    let edge = graph.find_edge(node, other_node);
+
+                    assert_eq!(edge.is_some(), segment.is_connected(other, connectivity));
+                    assert_eq!(
+                        edge.map_or(0, |edge| graph[edge].boundary_length),
+                        segment.boundary_length(other, connectivity)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn diagonal_boundaries() {
+        let mut graph = SegmentGraph::new_undirected();
+        graph.add_node(GeoSegment {
+            seg: Segment::from([(0, vec![0..=1]), (1, vec![0..=1])]),
+            ..GeoSegment::default()
+        });
+        graph.add_node(GeoSegment {
+            seg: Segment::from([(2, vec![2..=3])]),
+            ..GeoSegment::default()
+        });
+
+        connect_boundaries(&mut graph, Connectivity::Four);
+        assert_eq!(graph.edge_count(), 0);
+
+        connect_boundaries(&mut graph, Connectivity::Eight);
+        assert_eq!(graph.edge_count(), 1);
+        assert_eq!(graph.edge_weights().next().unwrap().boundary_length, 1);
     }
 
     #[test]
     fn neighbours() {
-        let segs = img_to_segs(r"img_segments\graph_2.png");
+        let segs = img_to_segs(Path::new("img_segments").join("graph_2.png"));
         let mut graph = SegmentGraph::new_undirected();
 
         for segment in segs.into_iter() {
@@ -118,7 +245,7 @@ mod tests {
         }
 
         let division = graph.node_count() / 3;
-        connect_neighbours(&mut graph, division);
+        connect_neighbours(&mut graph, division, 6);
 
         assert_eq!(graph.edge_count(), 36);
 
@@ -131,19 +258,47 @@ mod tests {
 
         assert!(max_dist < 3.0);
         assert!(max_dist > 2.0);
+
+        assert!(graph.edge_references().all(|edge| {
+            let (node_1, node_2) = (edge.source(), edge.target());
+            let distance = graph[node_1].calc_euclidean_distance(&graph[node_2]);
+
+            edge.weight().kind == EdgeKind::Candidate
+                && edge.weight().distance == distance
+                && edge.weight().tone_difference == graph[node_1].tone.abs_diff(graph[node_2].tone)
+        }));
+    }
+
+    #[test]
+    fn neighbour_count() {
+        let segs = img_to_segs(Path::new("img_segments").join("graph_2.png"));
+        let total = segs.len();
+        let (graph, division) = mount_graph(
+            segs.into_iter().take(total / 3).collect(),
+            img_to_segs(Path::new("img_segments").join("graph_2.png")),
+            Connectivity::Four,
+            3,
+        );
+
+        let candidates = graph
+            .edge_weights()
+            .filter(|edge| edge.kind == EdgeKind::Candidate)
+            .count();
+
+        assert_eq!(candidates, division * 3);
     }
 
     #[test]
     fn test_graph() {
-        let img = Reader::open(r"img_segments\segments.tif")
+        let img = Reader::open(Path::new("img_segments").join("segments.tif"))
             .unwrap()
             .decode()
             .unwrap()
             .to_luma_alpha8();
 
-        let segments = ImgSegmentation::segment_img(&img);
-        let b_segments = ImgSegmentation::segment_img(&img);
+        let segments = ImgSegmentation::segment_img(&img, Connectivity::Four).unwrap();
+        let b_segments = ImgSegmentation::segment_img(&img, Connectivity::Four).unwrap();
 
-        let _graph = mount_graph(segments, b_segments);
+        let _graph = mount_graph(segments, b_segments, Connectivity::Four, 6);
     }
 }
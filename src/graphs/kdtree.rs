@@ -0,0 +1,163 @@
+use std::collections::BinaryHeap;
+
+use ordered_float::OrderedFloat;
+
+use crate::helpers::CoordinatesF;
+
+/// 2-d tree over points tagged with an id, stored implicitly: the median of each slice splits it,
+/// alternating between x and y with the depth
+pub struct KdTree {
+    points: Vec<(CoordinatesF, usize)>,
+}
+
+#[inline]
+fn axis_value(point: CoordinatesF, depth: usize) -> f64 {
+    if depth.is_multiple_of(2) {
+        point.0
+    } else {
+        point.1
+    }
+}
+
+#[inline]
+fn squared_distance(a: CoordinatesF, b: CoordinatesF) -> f64 {
+    (a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)
+}
+
+impl KdTree {
+    pub fn new<I>(points: I) -> Self
+    where
+        I: IntoIterator<Item = (CoordinatesF, usize)>,
+    {
+        let mut points: Vec<(CoordinatesF, usize)> = points.into_iter().collect();
+        Self::build(&mut points, 0);
+
+        Self { points }
+    }
+
+    fn build(points: &mut [(CoordinatesF, usize)], depth: usize) {
+        if points.len() <= 1 {
+            return;
+        }
+
+        let mid = points.len() / 2;
+        points.select_nth_unstable_by(mid, |a, b| {
+            axis_value(a.0, depth).total_cmp(&axis_value(b.0, depth))
+        });
+
+        let (left, right) = points.split_at_mut(mid);
+        Self::build(left, depth + 1);
+        Self::build(&mut right[1..], depth + 1);
+    }
+
+    /// The `k` ids closest to `point` with their distances, from the closest. Ties go to the
+    /// smallest id.
+    pub fn nearest(&self, point: CoordinatesF, k: usize) -> Vec<(f64, usize)> {
+        if k == 0 {
+            return Vec::new();
+        }
+
+        // Max heap of squared distances, so the worst of the best is on top
+        let mut best = BinaryHeap::with_capacity(k + 1);
+        Self::search(&self.points, 0, point, k, &mut best);
+
+        best.into_sorted_vec()
+            .into_iter()
+            .map(|(distance, id): (OrderedFloat<f64>, usize)| (distance.sqrt(), id))
+            .collect()
+    }
+
+    fn search(
+        points: &[(CoordinatesF, usize)],
+        depth: usize,
+        point: CoordinatesF,
+        k: usize,
+        best: &mut BinaryHeap<(OrderedFloat<f64>, usize)>,
+    ) {
+        if points.is_empty() {
+            return;
+        }
+
+        let mid = points.len() / 2;
+        let (median, id) = points[mid];
+
+        best.push((OrderedFloat(squared_distance(point, median)), id));
+        if best.len() > k {
+            best.pop();
+        }
+
+        let diff = axis_value(point, depth) - axis_value(median, depth);
+        let (near, far) = if diff < 0.0 {
+            (&points[..mid], &points[mid + 1..])
+        } else {
+            (&points[mid + 1..], &points[..mid])
+        };
+
+        Self::search(near, depth + 1, point, k, best);
+
+        // Equal distances are still visited, so ties are settled by the ids
+        let worst = best
+            .peek()
+            .map_or(f64::INFINITY, |(distance, _)| distance.0);
+        if best.len() < k || diff.powi(2) <= worst {
+            Self::search(far, depth + 1, point, k, best);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KdTree;
+
+    /// Points on a small grid, so there are plenty of ties
+    fn points(n: usize) -> Vec<((f64, f64), usize)> {
+        let mut state: u64 = 12345;
+        let mut next = move || {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+            ((state >> 33) % 20) as f64
+        };
+
+        (0..n).map(|id| ((next(), next()), id)).collect()
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        let points = points(300);
+        let tree = KdTree::new(points.clone());
+
+        for query in [
+            (0.0, 0.0),
+            (7.5, 3.0),
+            (19.0, 19.0),
+            (10.0, 10.0),
+            (-4.0, 25.0),
+        ] {
+            for k in [1, 6, 17, 300, 400] {
+                let mut expected: Vec<(f64, usize)> = points
+                    .iter()
+                    .map(|(p, id)| (((p.0 - query.0).powi(2) + (p.1 - query.1).powi(2)), *id))
+                    .collect();
+                expected.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+                expected.truncate(k);
+
+                let found = tree.nearest(query, k);
+
+                assert_eq!(
+                    found.iter().map(|(_, id)| *id).collect::<Vec<_>>(),
+                    expected.iter().map(|(_, id)| *id).collect::<Vec<_>>()
+                );
+                for ((distance, _), (squared, _)) in found.iter().zip(expected.iter()) {
+                    assert!((distance - squared.sqrt()).abs() < 1e-9);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn empty() {
+        let tree = KdTree::new(Vec::new());
+
+        assert!(tree.nearest((1.0, 2.0), 3).is_empty());
+        assert!(KdTree::new(points(5)).nearest((1.0, 2.0), 0).is_empty());
+    }
+}
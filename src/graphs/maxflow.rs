@@ -0,0 +1,470 @@
+use std::collections::VecDeque;
+
+use petgraph::{graph::EdgeReference, visit::EdgeRef};
+
+use super::{SegmentEdge, SegmentGraph};
+
+const EPSILON: f64 = 1e-9;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Tree {
+    Free,
+    Source,
+    Sink,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Parent {
+    None,
+    Terminal,
+    /// Source tree -> arc from the parent to the node
+    /// Sink tree -> arc from the node to the parent
+    Arc(usize),
+}
+
+pub struct MinCut {
+    pub flow: f64,
+    /// Whether each node stays with the source after the cut
+    pub source_side: Vec<bool>,
+}
+
+/// Capacity graph with source and sink terminals, solved by the Boykov-Kolmogorov algorithm
+pub struct FlowGraph {
+    adjacency: Vec<Vec<usize>>,
+    /// Arc `a ^ 1` is the reverse of arc `a`
+    head: Vec<usize>,
+    residual: Vec<f64>,
+    /// Positive -> residual capacity from the source
+    /// Negative -> residual capacity to the sink
+    terminal: Vec<f64>,
+    flow: f64,
+
+    tree: Vec<Tree>,
+    parent: Vec<Parent>,
+    active: Vec<bool>,
+    active_queue: VecDeque<usize>,
+    orphans: VecDeque<usize>,
+}
+
+impl FlowGraph {
+    pub fn new(node_count: usize) -> Self {
+        Self {
+            adjacency: vec![Vec::new(); node_count],
+            head: Vec::new(),
+            residual: Vec::new(),
+            terminal: vec![0.0; node_count],
+            flow: 0.0,
+            tree: vec![Tree::Free; node_count],
+            parent: vec![Parent::None; node_count],
+            active: vec![false; node_count],
+            active_queue: VecDeque::new(),
+            orphans: VecDeque::new(),
+        }
+    }
+
+    /// One flow node per segment, with an edge in each direction for every edge of `graph`
+    pub fn from_segment_graph<F>(graph: &SegmentGraph, capacity: F) -> Self
+    where
+        F: Fn(EdgeReference<SegmentEdge>) -> f64,
+    {
+        let mut res = Self::new(graph.node_count());
+
+        for edge in graph.edge_references() {
+            let cap = capacity(edge);
+            res.add_edge(edge.source().index(), edge.target().index(), cap, cap);
+        }
+
+        res
+    }
+
+    #[inline]
+    pub fn node_count(&self) -> usize {
+        self.adjacency.len()
+    }
+
+    pub fn add_edge(&mut self, from: usize, to: usize, cap: f64, rev_cap: f64) {
+        self.adjacency[from].push(self.head.len());
+        self.head.push(to);
+        self.residual.push(cap);
+
+        self.adjacency[to].push(self.head.len());
+        self.head.push(from);
+        self.residual.push(rev_cap);
+    }
+
+    /// Capacities can be added many times for the same node
+    pub fn add_terminal_weights(&mut self, node: usize, source_cap: f64, sink_cap: f64) {
+        self.flow += source_cap.min(sink_cap);
+        self.terminal[node] += source_cap - sink_cap;
+    }
+
+    pub fn max_flow(mut self) -> MinCut {
+        for node in 0..self.node_count() {
+            if self.terminal[node] > EPSILON {
+                self.tree[node] = Tree::Source;
+            } else if self.terminal[node] < -EPSILON {
+                self.tree[node] = Tree::Sink;
+            } else {
+                continue;
+            }
+
+            self.parent[node] = Parent::Terminal;
+            self.activate(node);
+        }
+
+        while let Some(middle) = self.grow() {
+            self.augment(middle);
+            self.adopt();
+        }
+
+        MinCut {
+            flow: self.flow,
+            source_side: self.tree.iter().map(|t| *t == Tree::Source).collect(),
+        }
+    }
+
+    #[inline]
+    fn activate(&mut self, node: usize) {
+        if !self.active[node] {
+            self.active[node] = true;
+            self.active_queue.push_back(node);
+        }
+    }
+
+    /// Residual capacity of an arc in the direction flow moves inside `tree`
+    #[inline]
+    fn tree_cap(&self, tree: Tree, arc: usize) -> f64 {
+        match tree {
+            Tree::Source => self.residual[arc],
+            _ => self.residual[arc ^ 1],
+        }
+    }
+
+    /// Grows both trees until they touch, returning the arc from the source tree to the sink tree
+    fn grow(&mut self) -> Option<usize> {
+        while let Some(&node) = self.active_queue.front() {
+            let tree = self.tree[node];
+
+            if tree != Tree::Free {
+                for i in 0..self.adjacency[node].len() {
+                    let arc = self.adjacency[node][i];
+
+                    if self.tree_cap(tree, arc) <= EPSILON {
+                        continue;
+                    }
+
+                    let other = self.head[arc];
+
+                    match self.tree[other] {
+                        Tree::Free => {
+                            self.tree[other] = tree;
+                            self.parent[other] = match tree {
+                                Tree::Source => Parent::Arc(arc),
+                                _ => Parent::Arc(arc ^ 1),
+                            };
+                            self.activate(other);
+                        }
+                        other_tree if other_tree != tree => {
+                            return Some(match tree {
+                                Tree::Source => arc,
+                                _ => arc ^ 1,
+                            });
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            self.active_queue.pop_front();
+            self.active[node] = false;
+        }
+
+        None
+    }
+
+    /// Node at the other end of the parent arc
+    #[inline]
+    fn parent_node(&self, node: usize) -> Option<usize> {
+        match self.parent[node] {
+            Parent::Arc(arc) => Some(match self.tree[node] {
+                Tree::Source => self.head[arc ^ 1],
+                _ => self.head[arc],
+            }),
+            _ => None,
+        }
+    }
+
+    fn augment(&mut self, middle: usize) {
+        let s_start = self.head[middle ^ 1];
+        let t_start = self.head[middle];
+
+        let mut bottleneck = self.residual[middle];
+
+        for start in [s_start, t_start] {
+            let mut node = start;
+            while let Parent::Arc(arc) = self.parent[node] {
+                bottleneck = bottleneck.min(self.residual[arc]);
+                node = self.parent_node(node).unwrap();
+            }
+            bottleneck = bottleneck.min(self.terminal[node].abs());
+        }
+
+        self.residual[middle] -= bottleneck;
+        self.residual[middle ^ 1] += bottleneck;
+
+        for (start, sign) in [(s_start, -1.0), (t_start, 1.0)] {
+            let mut node = start;
+            while let Parent::Arc(arc) = self.parent[node] {
+                let next = self.parent_node(node).unwrap();
+
+                self.residual[arc] -= bottleneck;
+                self.residual[arc ^ 1] += bottleneck;
+
+                if self.residual[arc] <= EPSILON {
+                    self.parent[node] = Parent::None;
+                    self.orphans.push_back(node);
+                }
+
+                node = next;
+            }
+
+            self.terminal[node] += sign * bottleneck;
+
+            if self.terminal[node].abs() <= EPSILON {
+                self.parent[node] = Parent::None;
+                self.orphans.push_back(node);
+            }
+        }
+
+        self.flow += bottleneck;
+    }
+
+    /// Whether the path of parents from `node` still reaches a terminal
+    fn has_origin(&self, mut node: usize) -> bool {
+        loop {
+            match self.parent[node] {
+                Parent::Terminal => return true,
+                Parent::None => return false,
+                Parent::Arc(_) => node = self.parent_node(node).unwrap(),
+            }
+        }
+    }
+
+    fn adopt(&mut self) {
+        while let Some(orphan) = self.orphans.pop_front() {
+            let tree = self.tree[orphan];
+
+            let new_parent = self.adjacency[orphan].iter().copied().find(|&arc| {
+                let other = self.head[arc];
+
+                self.tree[other] == tree
+                    && self.tree_cap(tree, arc ^ 1) > EPSILON
+                    && self.has_origin(other)
+            });
+
+            if let Some(arc) = new_parent {
+                self.parent[orphan] = match tree {
+                    Tree::Source => Parent::Arc(arc ^ 1),
+                    _ => Parent::Arc(arc),
+                };
+                continue;
+            }
+
+            for i in 0..self.adjacency[orphan].len() {
+                let arc = self.adjacency[orphan][i];
+                let other = self.head[arc];
+
+                if self.tree[other] != tree {
+                    continue;
+                }
+
+                if self.tree_cap(tree, arc ^ 1) > EPSILON {
+                    self.activate(other);
+                }
+
+                if self.parent_node(other) == Some(orphan) {
+                    self.parent[other] = Parent::None;
+                    self.orphans.push_back(other);
+                }
+            }
+
+            self.tree[orphan] = Tree::Free;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        graphs::{SegmentEdge, SegmentGraph},
+        segmentation::GeoSegment,
+    };
+
+    use super::FlowGraph;
+
+    fn assert_flow(flow: f64, expected: f64) {
+        assert!((flow - expected).abs() < 1e-6, "{flow} != {expected}");
+    }
+
+    #[test]
+    fn two_nodes() {
+        let mut graph = FlowGraph::new(2);
+        graph.add_terminal_weights(0, 3.0, 1.0);
+        graph.add_terminal_weights(1, 1.0, 3.0);
+        graph.add_edge(0, 1, 1.0, 0.0);
+
+        let cut = graph.max_flow();
+
+        assert_flow(cut.flow, 3.0);
+        assert_eq!(cut.source_side, vec![true, false]);
+    }
+
+    #[test]
+    fn textbook_network() {
+        // s -> v1 (16), s -> v2 (13), v3 -> t (20), v4 -> t (4)
+        let mut graph = FlowGraph::new(4);
+        graph.add_terminal_weights(0, 16.0, 0.0);
+        graph.add_terminal_weights(1, 13.0, 0.0);
+        graph.add_terminal_weights(2, 0.0, 20.0);
+        graph.add_terminal_weights(3, 0.0, 4.0);
+
+        graph.add_edge(0, 2, 12.0, 0.0);
+        graph.add_edge(1, 0, 4.0, 0.0);
+        graph.add_edge(1, 3, 14.0, 0.0);
+        graph.add_edge(2, 1, 9.0, 0.0);
+        graph.add_edge(3, 2, 7.0, 0.0);
+
+        let cut = graph.max_flow();
+
+        assert_flow(cut.flow, 23.0);
+        assert_eq!(cut.source_side, vec![true, true, false, true]);
+    }
+
+    #[test]
+    fn chain_with_bottleneck() {
+        // s -> 0 -> 1 -> 2 -> 3 -> t, weakest link between 1 and 2
+        let mut graph = FlowGraph::new(4);
+        graph.add_terminal_weights(0, 10.0, 0.0);
+        graph.add_terminal_weights(3, 0.0, 10.0);
+
+        graph.add_edge(0, 1, 5.0, 5.0);
+        graph.add_edge(1, 2, 2.5, 2.5);
+        graph.add_edge(2, 3, 5.0, 5.0);
+
+        let cut = graph.max_flow();
+
+        assert_flow(cut.flow, 2.5);
+        assert_eq!(cut.source_side, vec![true, true, false, false]);
+    }
+
+    #[test]
+    fn disconnected_terminals() {
+        let mut graph = FlowGraph::new(3);
+        graph.add_terminal_weights(0, 4.0, 0.0);
+        graph.add_terminal_weights(1, 2.0, 5.0);
+        graph.add_terminal_weights(2, 0.0, 4.0);
+
+        let cut = graph.max_flow();
+
+        assert_flow(cut.flow, 2.0);
+        assert_eq!(cut.source_side, vec![true, false, false]);
+    }
+
+    #[test]
+    fn reroutes_flow() {
+        // The greedy path s -> 0 -> 1 -> t has to be undone to reach the maximum
+        let mut graph = FlowGraph::new(4);
+        graph.add_terminal_weights(0, 1.0, 0.0);
+        graph.add_terminal_weights(2, 1.0, 0.0);
+        graph.add_terminal_weights(1, 0.0, 1.0);
+        graph.add_terminal_weights(3, 0.0, 1.0);
+
+        graph.add_edge(0, 1, 1.0, 0.0);
+        graph.add_edge(0, 3, 1.0, 0.0);
+        graph.add_edge(2, 1, 1.0, 0.0);
+
+        let cut = graph.max_flow();
+
+        assert_flow(cut.flow, 2.0);
+    }
+
+    #[test]
+    fn segment_graph() {
+        let mut segments = SegmentGraph::new_undirected();
+        let nodes: Vec<_> = (0..3)
+            .map(|_| segments.add_node(GeoSegment::default()))
+            .collect();
+        for pair in nodes.windows(2) {
+            let edge = SegmentEdge::boundary(&segments[pair[0]], &segments[pair[1]], 3);
+            segments.add_edge(pair[0], pair[1], edge);
+        }
+
+        let mut graph = FlowGraph::from_segment_graph(&segments, |edge| {
+            edge.weight().boundary_length as f64 / 2.0
+        });
+        graph.add_terminal_weights(0, 10.0, 0.0);
+        graph.add_terminal_weights(2, 0.0, 10.0);
+
+        let cut = graph.max_flow();
+
+        assert_flow(cut.flow, 1.5);
+        assert!(cut.source_side[0]);
+        assert!(!cut.source_side[2]);
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        // Small pseudo-random networks, compared against every possible cut
+        let mut seed: u64 = 7;
+        let mut next = || {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((seed >> 33) % 10) as f64
+        };
+
+        for _ in 0..50 {
+            let n = 5;
+            let terminals: Vec<(f64, f64)> = (0..n).map(|_| (next(), next())).collect();
+            let mut edges = Vec::new();
+            for i in 0..n {
+                for j in (i + 1)..n {
+                    edges.push((i, j, next(), next()));
+                }
+            }
+
+            let mut graph = FlowGraph::new(n);
+            for (node, &(source_cap, sink_cap)) in terminals.iter().enumerate() {
+                graph.add_terminal_weights(node, source_cap, sink_cap);
+            }
+            for &(i, j, cap, rev_cap) in edges.iter() {
+                graph.add_edge(i, j, cap, rev_cap);
+            }
+
+            let cut_cost = |source_side: &dyn Fn(usize) -> bool| {
+                let terminal_cost = terminals.iter().enumerate().fold(0.0, |acc, (node, t)| {
+                    acc + if source_side(node) { t.1 } else { t.0 }
+                });
+
+                edges
+                    .iter()
+                    .fold(terminal_cost, |acc, &(i, j, cap, rev_cap)| {
+                        match (source_side(i), source_side(j)) {
+                            (true, false) => acc + cap,
+                            (false, true) => acc + rev_cap,
+                            _ => acc,
+                        }
+                    })
+            };
+
+            let best = (0..1u32 << n)
+                .map(|mask| cut_cost(&|node| mask & (1 << node) != 0))
+                .fold(f64::INFINITY, f64::min);
+
+            let cut = graph.max_flow();
+
+            assert_flow(cut.flow, best);
+            assert_flow(cut_cost(&|node| cut.source_side[node]), best);
+        }
+    }
+}
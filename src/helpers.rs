@@ -1,17 +1,20 @@
 use std::path::Path;
 
-use egui::Pos2;
 use image::{io::Reader, GrayAlphaImage};
 
-use crate::segmentation::{ImageSegments, ImgSegmentation};
+use crate::segmentation::{Connectivity, ImageSegments, ImgSegmentation};
 
-pub type Coordinates = (u16, u16);
+pub type Coordinates = (u32, u32);
 pub type CoordinatesF = (f64, f64);
 
 const MINIMUM_TRANSPARENCY: u8 = 0;
 
 pub trait Connected {
-    fn is_connected(&self, other: &Self) -> bool;
+    fn is_connected(&self, other: &Self, connectivity: Connectivity) -> bool;
+}
+
+pub trait Boundary {
+    fn boundary_length(&self, other: &Self, connectivity: Connectivity) -> usize;
 }
 
 pub trait Overlaps {
@@ -22,6 +25,10 @@ pub trait Crop {
     fn crop(&self, other: Self) -> Self;
 }
 
+pub trait Area {
+    fn area(&self) -> usize;
+}
+
 pub trait Centroid {
     fn calc_centroid(&self, img: &GrayAlphaImage) -> CoordinatesF;
 }
@@ -60,7 +67,7 @@ pub trait SmallCoord {
 impl SmallCoord for GrayAlphaImage {
     #[inline]
     fn get_pixel_s(&self, coords: Coordinates) -> [u8; 2] {
-        self.get_pixel(coords.0 as u32, coords.1 as u32).0
+        self.get_pixel(coords.0, coords.1).0
     }
 }
 
@@ -74,12 +81,5 @@ where
         .unwrap()
         .to_luma_alpha8();
 
-    ImgSegmentation::segment_img(&img)
-}
-
-pub fn no_nonsense_sub(lhs: Pos2, rhs: Pos2) -> Pos2 {
-    Pos2 {
-        x: lhs.x - rhs.x,
-        y: lhs.y - rhs.y,
-    }
+    ImgSegmentation::segment_img(&img, Connectivity::Four).unwrap()
 }
@@ -0,0 +1,27 @@
+//! Camouflage Images: hides a foreground image inside a background, so it takes the background's
+//! tones and texture while keeping its own contrast.
+//!
+//! The whole pipeline runs through [`camouflage::camouflage_img`]:
+//!
+//! ```no_run
+//! use camouflage_images::camouflage::{camouflage_img, CamouflageParams};
+//!
+//! let background = image::open("background.png").unwrap();
+//! let foreground = image::open("foreground.png").unwrap();
+//!
+//! let camouflaged = camouflage_img(&background, &foreground, (40, 25), &CamouflageParams::default())
+//!     .unwrap();
+//! camouflaged.save("camouflaged.png").unwrap();
+//! ```
+//!
+//! Its stages are exposed on their own: [`quantization`] of the luminance, [`segmentation`] into
+//! segments of the same tone, the [`graphs`] of neighbouring segments, the labeling and remapping
+//! in [`camouflage`], and the texture [`synthesis`].
+
+pub mod camouflage;
+pub mod colour;
+pub mod graphs;
+pub mod helpers;
+pub mod quantization;
+pub mod segmentation;
+pub mod synthesis;
@@ -1,15 +1,25 @@
-#![feature(iter_advance_by)]
-#![feature(duration_constants)]
-pub mod app;
-mod camouflage;
-mod graphs;
-pub mod helpers;
-pub mod quantization;
-pub mod segmentation;
-
-use crate::app::CamouflageImages;
-
-fn main() -> Result<(), eframe::Error> {
+mod cli;
+
+#[cfg(feature = "gui")]
+mod app;
+
+use std::error::Error;
+
+use clap::Parser;
+
+use crate::cli::{Cli, Command};
+
+fn main() -> Result<(), Box<dyn Error>> {
+    match Cli::parse().command {
+        Some(Command::Camouflage(args)) => cli::run(args),
+        Some(Command::Palette(args)) => cli::run_palette(args),
+        Some(Command::Segment(args)) => cli::run_segment(args),
+        None => run_gui(),
+    }
+}
+
+#[cfg(feature = "gui")]
+fn run_gui() -> Result<(), Box<dyn Error>> {
     let options = eframe::NativeOptions {
         maximized: true,
         ..Default::default()
@@ -19,5 +29,12 @@ fn main() -> Result<(), eframe::Error> {
         "Camouflage Images",
         options,
         Box::new(|_cc| Box::<app::CamouflageImages>::default()),
-    )
+    )?;
+
+    Ok(())
+}
+
+#[cfg(not(feature = "gui"))]
+fn run_gui() -> Result<(), Box<dyn Error>> {
+    Err("built without the `gui` feature, use the `camouflage` subcommand".into())
 }
@@ -1,48 +1,606 @@
-use std::collections::HashSet;
+pub mod palette;
+pub mod smoothing;
 
-use image::{GrayAlphaImage, LumaA};
+use std::{
+    collections::HashSet,
+    error::Error,
+    fmt::{self, Display, Formatter},
+    ops::RangeInclusive,
+};
 
-pub fn quantize(img: &mut GrayAlphaImage, n: u8) {
-    let t_1 = img.pixels().min_by_key(|p| p[0]).unwrap().0[0];
-    let t_2 = img.pixels().max_by_key(|p| p[0]).unwrap().0[0];
+use image::GrayAlphaImage;
 
-    let tam_int = t_2 - t_1;
+#[derive(Debug, PartialEq, Eq)]
+pub enum QuantizationError {
+    /// Quantizing to no levels at all
+    NoLevels,
+}
+
+impl Display for QuantizationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            QuantizationError::NoLevels => write!(f, "cannot quantize to zero levels"),
+        }
+    }
+}
+
+impl Error for QuantizationError {}
+
+/// Smallest and largest tones of the visible pixels
+pub fn visible_range(img: &GrayAlphaImage) -> Option<(u8, u8)> {
+    img.pixels()
+        .filter(|p| p[1] > 0)
+        .fold(None, |range, p| match range {
+            None => Some((p[0], p[0])),
+            Some((min, max)) => Some((min.min(p[0]), max.max(p[0]))),
+        })
+}
+
+/// How the visible tones of an image are grouped into levels, each level taking a single tone
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Quantizer {
+    /// Evenly spaced bins between the minimum and maximum tones, each taking its middle tone
+    #[default]
+    Uniform,
+    /// Ranges of tones maximizing the variance between them, found exactly
+    Otsu,
+    /// Lloyd iterations on the histogram, starting from levels at its quantiles
+    KMeans,
+    /// Lloyd iterations on the histogram, starting from evenly spaced levels
+    LloydMax,
+}
+
+impl Quantizer {
+    pub const ALL: [Quantizer; 4] = [
+        Quantizer::Uniform,
+        Quantizer::Otsu,
+        Quantizer::KMeans,
+        Quantizer::LloydMax,
+    ];
+
+    /// Reduces the luminance of the visible pixels to at most `levels` tones, keeping the order
+    /// of the tones. Transparent pixels are left as they are.
+    pub fn quantize(
+        &self,
+        img: &mut GrayAlphaImage,
+        levels: usize,
+    ) -> Result<(), QuantizationError> {
+        if levels == 0 {
+            return Err(QuantizationError::NoLevels);
+        }
+
+        let lut = match self {
+            Quantizer::Uniform => uniform(img, levels),
+            Quantizer::Otsu => histogram_lut(img, levels, otsu),
+            Quantizer::KMeans => histogram_lut(img, levels, |tones, levels| {
+                lloyd(tones, quantiles(tones, levels))
+            }),
+            Quantizer::LloydMax => histogram_lut(img, levels, |tones, levels| {
+                lloyd(tones, evenly_spaced(tones, levels))
+            }),
+        };
+
+        if let Some(lut) = lut {
+            for pixel in img.pixels_mut().filter(|p| p[1] > 0) {
+                pixel[0] = lut[pixel[0] as usize];
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// How many levels an image is quantized to
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Levels {
+    /// Chosen from the histogram by [`auto_levels`]
+    #[default]
+    Auto,
+    Fixed(usize),
+}
+
+impl Levels {
+    pub fn count(&self, img: &GrayAlphaImage) -> usize {
+        match self {
+            Levels::Auto => auto_levels(img),
+            Levels::Fixed(levels) => *levels,
+        }
+    }
+}
+
+/// Bounds of the level counts chosen by [`auto_levels`]
+pub const AUTO_LEVELS: RangeInclusive<usize> = 2..=16;
+
+/// Peaks lower than this fraction of the highest one are left out by [`auto_levels`]
+const MIN_PEAK: f64 = 0.05;
+
+/// One level per peak of the smoothed histogram of the visible tones, within [`AUTO_LEVELS`]
+pub fn auto_levels(img: &GrayAlphaImage) -> usize {
+    let histogram = smooth(weights(img));
+    let threshold = histogram.iter().copied().fold(0.0, f64::max) * MIN_PEAK;
+
+    let mut peaks = 0;
+    let mut start = 0;
+
+    // Runs of equal values, so that flat tops count once
+    while start < histogram.len() {
+        let value = histogram[start];
+        let end = (start..histogram.len())
+            .take_while(|t| histogram[*t] == value)
+            .last()
+            .unwrap();
+
+        let left = start.checked_sub(1).map_or(0.0, |t| histogram[t]);
+        let right = histogram.get(end + 1).copied().unwrap_or(0.0);
+
+        if value > left && value > right && value >= threshold {
+            peaks += 1;
+        }
+        start = end + 1;
+    }
+
+    peaks.clamp(*AUTO_LEVELS.start(), *AUTO_LEVELS.end())
+}
+
+/// Repeated box filters, close to a gaussian one, with no weight outside of the tones
+fn smooth(mut histogram: [f64; 256]) -> [f64; 256] {
+    const RADIUS: usize = 2;
+    const PASSES: usize = 2;
+
+    for _ in 0..PASSES {
+        let previous = histogram;
+
+        for (t, value) in histogram.iter_mut().enumerate() {
+            let window = t.saturating_sub(RADIUS)..(t + RADIUS + 1).min(previous.len());
+            *value = previous[window].iter().sum::<f64>() / (2 * RADIUS + 1) as f64;
+        }
+    }
+
+    histogram
+}
+
+/// Same as [`Quantizer::Uniform`]
+pub fn quantize(img: &mut GrayAlphaImage, levels: usize) -> Result<(), QuantizationError> {
+    Quantizer::Uniform.quantize(img, levels)
+}
+
+/// New tone of each tone, `None` when every tone keeps its own level
+type Lut = Option<[u8; 256]>;
+
+fn uniform(img: &GrayAlphaImage, levels: usize) -> Lut {
+    let (t_1, t_2) = visible_range(img)?;
+
+    // Tones in the range, so that each one keeps its own bin with enough levels
+    let span = (t_2 - t_1) as usize + 1;
+    if levels >= span {
+        return None;
+    }
+
+    // First tone of each bin, from the start of the range, with the end of the last one after
+    let starts: Vec<usize> = (0..=levels).map(|i| (i * span).div_ceil(levels)).collect();
+
+    let mut lut = [0; 256];
+    for (offset, tone) in (t_1..=t_2).enumerate() {
+        // Below `levels`, since `offset` is below `span`
+        let bin = offset * levels / span;
+        let middle = (starts[bin] + starts[bin + 1] - 1) / 2;
+
+        lut[tone as usize] = t_1 + middle as u8;
+    }
+
+    Some(lut)
+}
+
+/// Total opacity of the pixels of each tone
+fn weights(img: &GrayAlphaImage) -> [f64; 256] {
+    let mut res = [0.0; 256];
+
+    for p in img.pixels() {
+        res[p[0] as usize] += p[1] as f64 / u8::MAX as f64;
+    }
+
+    res
+}
+
+/// Visible tones with their total opacity, from the darkest
+fn histogram(img: &GrayAlphaImage) -> Vec<(u8, f64)> {
+    (0..=u8::MAX)
+        .zip(weights(img))
+        .filter(|(_, weight)| *weight > 0.0)
+        .collect()
+}
+
+/// Groups the tones of the histogram with `classify`, which gives the class of each tone, and
+/// maps each class to its mean tone
+fn histogram_lut<F>(img: &GrayAlphaImage, levels: usize, classify: F) -> Lut
+where
+    F: Fn(&[(u8, f64)], usize) -> Vec<usize>,
+{
+    let tones = histogram(img);
+    if levels >= tones.len() {
+        return None;
+    }
+
+    let classes = classify(&tones, levels);
+    let mut sums = vec![(0.0, 0.0); levels];
+
+    for ((tone, weight), class) in tones.iter().zip(classes.iter()) {
+        sums[*class].0 += *tone as f64 * weight;
+        sums[*class].1 += weight;
+    }
+
+    let mut lut = [0; 256];
+    for ((tone, _), class) in tones.iter().zip(classes) {
+        let (sum, weight) = sums[class];
+        lut[*tone as usize] = (sum / weight).round() as u8;
+    }
+
+    Some(lut)
+}
+
+/// Multi-level Otsu by dynamic programming over the ways of splitting the tones into `levels`
+/// ranges, maximizing the sum of `weight * mean²` of the ranges
+fn otsu(tones: &[(u8, f64)], levels: usize) -> Vec<usize> {
+    let n = tones.len();
+
+    // Prefix sums of the weights and of the weighted tones
+    let mut weights = vec![0.0; n + 1];
+    let mut sums = vec![0.0; n + 1];
+    for (i, (tone, weight)) in tones.iter().enumerate() {
+        weights[i + 1] = weights[i] + weight;
+        sums[i + 1] = sums[i] + *tone as f64 * weight;
+    }
+    let score = |i: usize, j: usize| (sums[j] - sums[i]).powi(2) / (weights[j] - weights[i]);
+
+    // Best score of the first `j` tones split into `k` ranges, with where the last range starts
+    let mut best = vec![vec![f64::NEG_INFINITY; n + 1]; levels + 1];
+    let mut starts = vec![vec![0; n + 1]; levels + 1];
+    best[0][0] = 0.0;
+
+    for k in 1..=levels {
+        for j in k..=n - (levels - k) {
+            for i in k - 1..j {
+                let candidate = best[k - 1][i] + score(i, j);
+
+                if candidate > best[k][j] {
+                    best[k][j] = candidate;
+                    starts[k][j] = i;
+                }
+            }
+        }
+    }
+
+    let mut classes = vec![0; n];
+    let mut end = n;
+    for k in (1..=levels).rev() {
+        let start = starts[k][end];
+        classes[start..end].fill(k - 1);
+        end = start;
+    }
+
+    classes
+}
+
+/// Tones splitting the histogram into `levels` parts of the same weight, at the middle of each
+fn quantiles(tones: &[(u8, f64)], levels: usize) -> Vec<f64> {
+    let total: f64 = tones.iter().map(|(_, weight)| weight).sum();
+    let mut res = Vec::with_capacity(levels);
+    let (mut i, mut sum) = (0, tones[0].1);
 
-    if n < tam_int {
-        let tb: f64 = tam_int as f64 / n as f64;
-        let center_offset: f64 = tam_int as f64 / (2 * n) as f64;
-        let mut bin_vec: Vec<u8> = Vec::with_capacity(n as usize + 1);
+    for level in 0..levels {
+        let target = (level as f64 + 0.5) / levels as f64 * total;
 
-        for i in (t_1..t_2).step_by(tb.round() as usize) {
-            bin_vec.push(i + center_offset.round() as u8);
+        while sum < target && i + 1 < tones.len() {
+            i += 1;
+            sum += tones[i].1;
         }
-        bin_vec.push(*bin_vec.last().unwrap());
+        res.push(tones[i].0 as f64);
+    }
+
+    res
+}
 
-        for h in 0..img.height() {
-            for w in 0..img.width() {
-                let pixel = img.get_pixel(w, h);
-                let bin_i = usize::from(pixel[0] - t_1) / tb.round() as usize;
-                let quantized_value = bin_vec[bin_i];
+/// Middles of `levels` bins of the same width between the darkest and the lightest tones
+fn evenly_spaced(tones: &[(u8, f64)], levels: usize) -> Vec<f64> {
+    let t_1 = tones[0].0 as f64;
+    let span = tones[tones.len() - 1].0 as f64 - t_1 + 1.0;
 
-                let quantized_pixel = LumaA::<u8>([quantized_value, pixel[1]]);
+    (0..levels)
+        .map(|level| t_1 + (level as f64 + 0.5) * span / levels as f64 - 0.5)
+        .collect()
+}
+
+/// Moves the levels to the mean of the tones closest to them until no tone changes level,
+/// giving the level of each tone
+fn lloyd(tones: &[(u8, f64)], mut levels: Vec<f64>) -> Vec<usize> {
+    const MAX_ITERATIONS: usize = 100;
+
+    let closest = |levels: &[f64], tone: u8| {
+        (0..levels.len())
+            .min_by(|a, b| {
+                let a = (levels[*a] - tone as f64).abs();
+                let b = (levels[*b] - tone as f64).abs();
+                a.total_cmp(&b)
+            })
+            .unwrap()
+    };
+
+    let mut classes: Vec<usize> = tones
+        .iter()
+        .map(|(tone, _)| closest(&levels, *tone))
+        .collect();
+
+    for _ in 0..MAX_ITERATIONS {
+        let mut sums = vec![(0.0, 0.0); levels.len()];
+        for ((tone, weight), class) in tones.iter().zip(classes.iter()) {
+            sums[*class].0 += *tone as f64 * weight;
+            sums[*class].1 += weight;
+        }
 
-                img.put_pixel(w, h, quantized_pixel);
+        // Levels without tones stay where they are
+        for (level, (sum, weight)) in levels.iter_mut().zip(sums) {
+            if weight > 0.0 {
+                *level = sum / weight;
             }
         }
+
+        let next: Vec<usize> = tones
+            .iter()
+            .map(|(tone, _)| closest(&levels, *tone))
+            .collect();
+        if next == classes {
+            break;
+        }
+        classes = next;
     }
+
+    classes
 }
 
-pub fn calc_tones(img: &GrayAlphaImage) -> u8 {
+/// Number of distinct tones among the visible pixels
+pub fn calc_tones(img: &GrayAlphaImage) -> usize {
     let mut set = HashSet::new();
 
     for p in img.pixels().filter(|p| p.0[1] > 0) {
         set.insert(p.0[0]);
     }
 
-    set.len() as u8
+    set.len()
 }
 
-pub fn quantize_by_tones(img: &mut GrayAlphaImage) {
+/// Quantizes with as many levels as the image has visible tones
+pub fn quantize_by_tones(img: &mut GrayAlphaImage) -> Result<(), QuantizationError> {
     let tones = calc_tones(img);
-    quantize(img, tones);
+    quantize(img, tones.max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayAlphaImage, LumaA};
+    use proptest::prelude::*;
+
+    use crate::segmentation::{Connectivity, ImgSegmentation};
+
+    use super::{
+        auto_levels, calc_tones, quantize, quantize_by_tones, visible_range, Levels,
+        QuantizationError, Quantizer, AUTO_LEVELS,
+    };
+
+    #[test]
+    fn degenerate_images() {
+        let mut empty = GrayAlphaImage::new(0, 0);
+        let mut transparent = GrayAlphaImage::from_pixel(4, 3, LumaA([70, 0]));
+        let mut flat = GrayAlphaImage::from_pixel(4, 3, LumaA([70, 255]));
+
+        for img in [&mut empty, &mut transparent, &mut flat] {
+            let original = img.clone();
+
+            for quantizer in Quantizer::ALL {
+                assert_eq!(quantizer.quantize(img, 3), Ok(()));
+                assert_eq!(quantize_by_tones(img), Ok(()));
+                assert_eq!(*img, original);
+                assert_eq!(quantizer.quantize(img, 0), Err(QuantizationError::NoLevels));
+            }
+        }
+    }
+
+    #[test]
+    fn every_tone() {
+        let mut img = GrayAlphaImage::from_fn(16, 16, |x, y| LumaA([(x + y * 16) as u8, 255]));
+
+        assert_eq!(calc_tones(&img), 256);
+
+        quantize(&mut img, 4).unwrap();
+
+        assert_eq!(calc_tones(&img), 4);
+        assert_eq!(img.get_pixel(0, 0)[0], 31);
+        assert_eq!(img.get_pixel(15, 15)[0], 223);
+    }
+
+    #[test]
+    fn transparent_outliers() {
+        // The transparent pixel is far outside the range of the visible ones
+        let mut img = GrayAlphaImage::from_fn(10, 1, |x, _| match x {
+            0 => LumaA([250, 0]),
+            _ => LumaA([100 + x as u8, 255]),
+        });
+
+        quantize(&mut img, 3).unwrap();
+
+        assert_eq!(*img.get_pixel(0, 0), LumaA([250, 0]));
+        assert_eq!(visible_range(&img), Some((102, 108)));
+    }
+
+    #[test]
+    fn histogram_driven() {
+        // Most pixels are dark, a few are light, and a single one is white
+        let mut img = GrayAlphaImage::from_fn(100, 1, |x, _| match x {
+            0..=69 => LumaA([20 + x as u8 % 11, 255]),
+            70..=98 => LumaA([190 + x as u8 % 11, 255]),
+            _ => LumaA([255, 255]),
+        });
+        let original = img.clone();
+
+        quantize(&mut img, 2).unwrap();
+        // The white pixel stretches the even bins, so the dark ones end up far from their tones
+        assert_eq!(img.get_pixel(0, 0)[0], 78);
+
+        for quantizer in [Quantizer::Otsu, Quantizer::KMeans, Quantizer::LloydMax] {
+            let mut img = original.clone();
+            quantizer.quantize(&mut img, 2).unwrap();
+
+            let dark = img.get_pixel(0, 0)[0];
+            let light = img.get_pixel(70, 0)[0];
+
+            assert!((20..=30).contains(&dark), "{quantizer:?}");
+            assert!((190..=210).contains(&light), "{quantizer:?}");
+            assert!(img.pixels().all(|p| p[0] == dark || p[0] == light));
+        }
+    }
+
+    #[test]
+    fn opacity_weights() {
+        // Two equally common tones and a third one, heavier than either when it is opaque
+        let img = |alpha| {
+            GrayAlphaImage::from_fn(9, 1, |x, _| match x {
+                0..=1 => LumaA([10, 255]),
+                2..=3 => LumaA([100, 255]),
+                _ => LumaA([240, alpha]),
+            })
+        };
+
+        let mut opaque = img(255);
+        let mut faint = img(10);
+        Quantizer::Otsu.quantize(&mut opaque, 2).unwrap();
+        Quantizer::Otsu.quantize(&mut faint, 2).unwrap();
+
+        // The light pixels keep their own level only while they weigh enough
+        assert_eq!(opaque.get_pixel(0, 0)[0], opaque.get_pixel(2, 0)[0]);
+        assert_eq!(faint.get_pixel(2, 0)[0], faint.get_pixel(8, 0)[0]);
+    }
+
+    /// Pixels spread around three tones, with a little noise everywhere
+    fn three_peaks() -> GrayAlphaImage {
+        let mut state: u64 = 2024;
+
+        GrayAlphaImage::from_fn(90, 60, |x, _| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+            let random = (state >> 33) as u32;
+
+            let centre = [40, 120, 210][x as usize / 30];
+            let tone = if random.is_multiple_of(20) {
+                random >> 8
+            } else {
+                // Sum of two uniform offsets, most often close to the centre
+                centre + random % 7 + random / 7 % 7 - 6
+            };
+            LumaA([tone as u8, 255])
+        })
+    }
+
+    #[test]
+    fn automatic_levels() {
+        let img = three_peaks();
+
+        assert_eq!(auto_levels(&img), 3);
+        assert_eq!(Levels::Fixed(7).count(&img), 7);
+        assert_eq!(
+            auto_levels(&GrayAlphaImage::from_pixel(5, 5, LumaA([90, 255]))),
+            *AUTO_LEVELS.start()
+        );
+        assert_eq!(
+            auto_levels(&GrayAlphaImage::new(0, 0)),
+            *AUTO_LEVELS.start()
+        );
+
+        // Far fewer segments than when keeping a level per tone
+        let mut by_tones = img.clone();
+        let mut auto = img.clone();
+        quantize_by_tones(&mut by_tones).unwrap();
+        Quantizer::Otsu
+            .quantize(&mut auto, Levels::Auto.count(&img))
+            .unwrap();
+
+        let by_tones = ImgSegmentation::segment_img(&by_tones, Connectivity::Four).unwrap();
+        let auto = ImgSegmentation::segment_img(&auto, Connectivity::Four).unwrap();
+        assert!(auto.len() * 10 < by_tones.len());
+    }
+
+    #[test]
+    fn hidden_peaks() {
+        // The transparent pixels would make a peak of their own
+        let img = GrayAlphaImage::from_fn(30, 10, |x, _| match x {
+            0..=9 => LumaA([30, 255]),
+            10..=19 => LumaA([130, 0]),
+            _ => LumaA([230, 255]),
+        });
+
+        assert_eq!(auto_levels(&img), 2);
+    }
+
+    fn image() -> impl Strategy<Value = GrayAlphaImage> {
+        (0..12u32, 0..12u32).prop_flat_map(|(width, height)| {
+            let tones = prop_oneof![Just(0u8), Just(255u8), any::<u8>()];
+            let alphas = prop_oneof![Just(0u8), Just(255u8), any::<u8>()];
+
+            prop::collection::vec((tones, alphas), (width * height) as usize).prop_map(
+                move |pixels| {
+                    let raw = pixels.into_iter().flat_map(|(l, a)| [l, a]).collect();
+                    GrayAlphaImage::from_raw(width, height, raw).unwrap()
+                },
+            )
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn valid_bins(original in image(), levels in 1..300usize) {
+            for quantizer in Quantizer::ALL {
+                let mut img = original.clone();
+                quantizer.quantize(&mut img, levels).unwrap();
+
+                prop_assert!(calc_tones(&img) <= levels.min(calc_tones(&original)));
+
+                let range = visible_range(&original);
+                for (before, after) in original.pixels().zip(img.pixels()) {
+                    prop_assert_eq!(before[1], after[1]);
+
+                    match range {
+                        Some((t_1, t_2)) if before[1] > 0 => {
+                            prop_assert!((t_1..=t_2).contains(&after[0]));
+                        }
+                        _ => prop_assert_eq!(before[0], after[0]),
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn keeps_order(original in image(), levels in 1..20usize) {
+            for quantizer in Quantizer::ALL {
+                let mut img = original.clone();
+                quantizer.quantize(&mut img, levels).unwrap();
+
+                let mut visible: Vec<(u8, u8)> = original
+                    .pixels()
+                    .zip(img.pixels())
+                    .filter(|(p, _)| p[1] > 0)
+                    .map(|(before, after)| (before[0], after[0]))
+                    .collect();
+                visible.sort();
+
+                for (a, b) in visible.iter().zip(visible.iter().skip(1)) {
+                    prop_assert!(a.1 <= b.1, "{:?}", quantizer);
+                }
+            }
+        }
+
+        #[test]
+        fn by_tones(original in image()) {
+            let mut img = original.clone();
+
+            prop_assert_eq!(quantize_by_tones(&mut img), Ok(()));
+            prop_assert!(calc_tones(&img) <= calc_tones(&original));
+        }
+    }
 }
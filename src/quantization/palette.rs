@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+
+use image::{GrayAlphaImage, LumaA, Pixel, Rgb, RgbImage, Rgba, RgbaImage};
+
+use crate::{
+    colour::{distance, Colour, ColourSpace},
+    segmentation::{Connectivity, ImageSegments, ImgSegmentation, SegmentationError},
+};
+
+use super::QuantizationError;
+
+/// How the colours of a palette are chosen, always in CIELAB
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ColourQuantizer {
+    /// Splits the colours at the weighted median of their longest side until there are enough
+    /// groups
+    MedianCut,
+    /// Lloyd iterations starting from the median cut palette
+    #[default]
+    KMeans,
+}
+
+/// Image reduced to a few colours
+pub struct Palette {
+    pub colours: Vec<[u8; 3]>,
+    /// Index of the palette colour of each pixel, along with the pixel's alpha
+    pub indices: GrayAlphaImage,
+}
+
+/// Most colours a palette can hold, so that indices fit in the pixels
+pub const MAX_COLOURS: usize = 256;
+
+/// Distinct visible colours in CIELAB with their total opacity, in the order of their RGB values
+fn colour_histogram(img: &RgbaImage) -> Vec<([u8; 3], Colour, f64)> {
+    let mut weights: HashMap<[u8; 3], f64> = HashMap::new();
+
+    for p in img.pixels().filter(|p| p[3] > 0) {
+        *weights.entry(p.to_rgb().0).or_insert(0.0) += p[3] as f64 / u8::MAX as f64;
+    }
+
+    let mut res: Vec<([u8; 3], Colour, f64)> = weights
+        .into_iter()
+        .map(|(rgb, weight)| (rgb, ColourSpace::Lab.from_rgb(rgb), weight))
+        .collect();
+    res.sort_by_key(|(rgb, _, _)| *rgb);
+
+    res
+}
+
+/// Weighted mean of some of the colours
+fn mean(colours: &[([u8; 3], Colour, f64)], members: &[usize]) -> Colour {
+    let mut sum = [0.0; 3];
+    let mut total = 0.0;
+
+    for i in members {
+        let (_, colour, weight) = colours[*i];
+        for (s, c) in sum.iter_mut().zip(colour) {
+            *s += c * weight;
+        }
+        total += weight;
+    }
+
+    sum.map(|s| s / total)
+}
+
+/// Groups of colours, at most `count` of them
+fn median_cut(colours: &[([u8; 3], Colour, f64)], count: usize) -> Vec<Vec<usize>> {
+    let mut groups = vec![(0..colours.len()).collect::<Vec<usize>>()];
+
+    // Longest side of the box around a group, with its axis
+    let side = |group: &[usize]| {
+        (0..3)
+            .map(|axis| {
+                let values = group.iter().map(|i| colours[*i].1[axis]);
+                let min = values.clone().fold(f64::INFINITY, f64::min);
+                let max = values.fold(f64::NEG_INFINITY, f64::max);
+                (max - min, axis)
+            })
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .unwrap()
+    };
+
+    while groups.len() < count {
+        let Some((g, (_, axis))) = groups
+            .iter()
+            .enumerate()
+            .filter(|(_, group)| group.len() > 1)
+            .map(|(g, group)| (g, side(group)))
+            .max_by(|a, b| a.1 .0.total_cmp(&b.1 .0))
+        else {
+            break;
+        };
+
+        let mut group = groups.swap_remove(g);
+        group.sort_by(|a, b| colours[*a].1[axis].total_cmp(&colours[*b].1[axis]));
+
+        // First colour past half of the weight, keeping a colour on each side
+        let half = group.iter().map(|i| colours[*i].2).sum::<f64>() / 2.0;
+        let mut weight = 0.0;
+        let split = group
+            .iter()
+            .position(|i| {
+                weight += colours[*i].2;
+                weight >= half
+            })
+            .map_or(1, |position| position + 1)
+            .clamp(1, group.len() - 1);
+
+        let rest = group.split_off(split);
+        groups.push(group);
+        groups.push(rest);
+    }
+
+    groups
+}
+
+/// Index of the closest of the centres
+#[inline]
+fn closest(centres: &[Colour], colour: &Colour) -> usize {
+    (0..centres.len())
+        .min_by(|a, b| distance(&centres[*a], colour).total_cmp(&distance(&centres[*b], colour)))
+        .unwrap()
+}
+
+/// Moves the centres to the mean of the colours closest to them until no colour changes centre
+fn k_means(colours: &[([u8; 3], Colour, f64)], mut centres: Vec<Colour>) -> Vec<Colour> {
+    const MAX_ITERATIONS: usize = 50;
+
+    let mut classes: Vec<usize> = colours.iter().map(|c| closest(&centres, &c.1)).collect();
+
+    for _ in 0..MAX_ITERATIONS {
+        let mut members = vec![Vec::new(); centres.len()];
+        for (i, class) in classes.iter().enumerate() {
+            members[*class].push(i);
+        }
+
+        // Centres without colours stay where they are
+        for (centre, members) in centres.iter_mut().zip(members) {
+            if !members.is_empty() {
+                *centre = mean(colours, &members);
+            }
+        }
+
+        let next: Vec<usize> = colours.iter().map(|c| closest(&centres, &c.1)).collect();
+        if next == classes {
+            break;
+        }
+        classes = next;
+    }
+
+    centres
+}
+
+impl Palette {
+    /// Reduces the visible pixels to at most `count` colours, or [`MAX_COLOURS`]. Transparent
+    /// pixels keep their alpha, with the index 0.
+    pub fn new(
+        img: &RgbaImage,
+        count: usize,
+        quantizer: ColourQuantizer,
+    ) -> Result<Self, QuantizationError> {
+        if count == 0 {
+            return Err(QuantizationError::NoLevels);
+        }
+
+        let colours = colour_histogram(img);
+        if colours.is_empty() {
+            return Ok(Self {
+                colours: Vec::new(),
+                indices: GrayAlphaImage::new(img.width(), img.height()),
+            });
+        }
+
+        let groups = median_cut(&colours, count.min(MAX_COLOURS));
+        let mut centres: Vec<Colour> = groups.iter().map(|group| mean(&colours, group)).collect();
+
+        if quantizer == ColourQuantizer::KMeans {
+            centres = k_means(&colours, centres);
+        }
+
+        let index: HashMap<[u8; 3], u8> = colours
+            .iter()
+            .map(|(rgb, colour, _)| (*rgb, closest(&centres, colour) as u8))
+            .collect();
+
+        let indices = GrayAlphaImage::from_fn(img.width(), img.height(), |x, y| {
+            let p = img.get_pixel(x, y);
+
+            match p[3] {
+                0 => LumaA([0, 0]),
+                alpha => LumaA([index[&p.to_rgb().0], alpha]),
+            }
+        });
+
+        Ok(Self {
+            colours: centres
+                .into_iter()
+                .map(|centre| ColourSpace::Lab.to_rgb(centre))
+                .collect(),
+            indices,
+        })
+    }
+
+    /// Image with the palette colours in place of the indices
+    pub fn to_rgba(&self) -> RgbaImage {
+        RgbaImage::from_fn(self.indices.width(), self.indices.height(), |x, y| {
+            let [index, alpha] = self.indices.get_pixel(x, y).0;
+
+            match alpha {
+                0 => Rgba([0, 0, 0, 0]),
+                _ => {
+                    let [r, g, b] = self.colours[index as usize];
+                    Rgba([r, g, b, alpha])
+                }
+            }
+        })
+    }
+
+    /// Row of squares of `size` pixels, one for each colour
+    pub fn swatches(&self, size: u32) -> RgbImage {
+        RgbImage::from_fn(size * self.colours.len() as u32, size, |x, _| {
+            Rgb(self.colours[(x / size) as usize])
+        })
+    }
+
+    /// Colours in `#rrggbb` notation
+    pub fn hex_codes(&self) -> Vec<String> {
+        self.colours
+            .iter()
+            .map(|[r, g, b]| format!("#{r:02x}{g:02x}{b:02x}"))
+            .collect()
+    }
+
+    /// Connected pixels with the same palette colour, taking the luminance of that colour as
+    /// their tone
+    pub fn segment(&self, connectivity: Connectivity) -> Result<ImageSegments, SegmentationError> {
+        let mut segments = ImgSegmentation::segment_img(&self.indices, connectivity)?;
+
+        for segment in segments.iter_mut() {
+            segment.tone = Rgb(self.colours[segment.tone as usize]).to_luma()[0];
+        }
+
+        Ok(segments)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Pixel, Rgb, Rgba, RgbaImage};
+
+    use crate::{
+        colour::{distance, ColourSpace},
+        quantization::QuantizationError,
+        segmentation::Connectivity,
+    };
+
+    use super::{ColourQuantizer, Palette};
+
+    const QUADRANTS: [[u8; 3]; 4] = [[200, 40, 40], [40, 160, 60], [30, 30, 180], [230, 220, 90]];
+
+    /// Noisy quadrants of four colours, with a transparent magenta border
+    fn quadrants() -> RgbaImage {
+        RgbaImage::from_fn(34, 34, |x, y| {
+            if x == 0 || y == 0 || x == 33 || y == 33 {
+                return Rgba([255, 0, 255, 0]);
+            }
+
+            let noise = ((x * 7 + y * 13) % 7) as u8;
+            let [r, g, b] = QUADRANTS[(x / 17 + 2 * (y / 17)) as usize];
+            Rgba([r + noise, g + noise, b + noise, 255])
+        })
+    }
+
+    #[test]
+    fn four_colours() {
+        let img = quadrants();
+
+        for quantizer in [ColourQuantizer::MedianCut, ColourQuantizer::KMeans] {
+            let palette = Palette::new(&img, 4, quantizer).unwrap();
+
+            assert_eq!(palette.colours.len(), 4);
+            for expected in QUADRANTS {
+                let expected = ColourSpace::Lab.from_rgb(expected);
+
+                assert!(palette
+                    .colours
+                    .iter()
+                    .any(|c| distance(&ColourSpace::Lab.from_rgb(*c), &expected) < 5.0));
+            }
+
+            // Transparent pixels keep their alpha, and every quadrant takes a single colour
+            assert_eq!(palette.indices.get_pixel(0, 0)[1], 0);
+            assert_eq!(palette.to_rgba().get_pixel(0, 0)[3], 0);
+            let segments = palette.segment(Connectivity::Four).unwrap();
+            assert_eq!(segments.len(), 4);
+
+            let tones: Vec<u8> = palette
+                .colours
+                .iter()
+                .map(|c| Rgb(*c).to_luma()[0])
+                .collect();
+            assert!(segments.iter().all(|s| tones.contains(&s.tone)));
+        }
+    }
+
+    #[test]
+    fn few_colours() {
+        let img = RgbaImage::from_fn(6, 2, |x, _| {
+            if x < 3 {
+                Rgba([10, 20, 30, 255])
+            } else {
+                Rgba([250, 240, 230, 255])
+            }
+        });
+
+        let palette = Palette::new(&img, 16, ColourQuantizer::KMeans).unwrap();
+
+        assert_eq!(palette.colours, vec![[10, 20, 30], [250, 240, 230]]);
+        assert_eq!(palette.to_rgba(), img);
+        assert_eq!(palette.hex_codes(), vec!["#0a141e", "#faf0e6"]);
+        assert_eq!(palette.swatches(8).dimensions(), (16, 8));
+        assert_eq!(
+            Palette::new(&img, 0, ColourQuantizer::MedianCut).err(),
+            Some(QuantizationError::NoLevels)
+        );
+    }
+
+    #[test]
+    fn transparent() {
+        let img = RgbaImage::from_pixel(3, 3, Rgba([1, 2, 3, 0]));
+        let palette = Palette::new(&img, 4, ColourQuantizer::KMeans).unwrap();
+
+        assert!(palette.colours.is_empty());
+        assert!(palette.segment(Connectivity::Four).unwrap().is_empty());
+    }
+}
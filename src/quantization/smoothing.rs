@@ -0,0 +1,189 @@
+use image::{GrayAlphaImage, LumaA};
+
+/// Spreads of the weights of a bilateral filter, which averages each pixel with the nearby
+/// pixels of a similar tone, smoothing gradients and noise while keeping edges
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BilateralParams {
+    /// Standard deviation of the distance to the neighbours, in pixels
+    pub spatial_sigma: f64,
+    /// Standard deviation of the difference with the tones of the neighbours
+    pub range_sigma: f64,
+}
+
+impl Default for BilateralParams {
+    fn default() -> Self {
+        Self {
+            spatial_sigma: 3.0,
+            range_sigma: 20.0,
+        }
+    }
+}
+
+#[inline]
+fn gaussian(distance: f64, sigma: f64) -> f64 {
+    (-distance.powi(2) / (2.0 * sigma.powi(2))).exp()
+}
+
+/// Bilateral filter over the visible pixels, each neighbour also weighted by its alpha.
+/// Transparent pixels are left as they are.
+pub fn bilateral(img: &GrayAlphaImage, params: &BilateralParams) -> GrayAlphaImage {
+    bilateral_with_progress(img, params, || true)
+        .expect("smoothing without a progress callback is never stopped")
+}
+
+/// Same as [`bilateral`], calling `progress` before each row.
+/// Gives up with `None` as soon as `progress` returns false.
+pub fn bilateral_with_progress<F>(
+    img: &GrayAlphaImage,
+    params: &BilateralParams,
+    mut progress: F,
+) -> Option<GrayAlphaImage>
+where
+    F: FnMut() -> bool,
+{
+    let (width, height) = img.dimensions();
+    let spatial_sigma = params.spatial_sigma.max(f64::EPSILON);
+    let range_sigma = params.range_sigma.max(f64::EPSILON);
+    // Past the image, a wider window only holds pixels that are skipped
+    let radius = (2.0 * spatial_sigma).ceil().min(width.max(height) as f64) as i64;
+
+    // Weights of the offsets within the radius, and of the tone differences
+    let side = 2 * radius as usize + 1;
+    let spatial: Vec<f64> = (-radius..=radius)
+        .flat_map(|dy| (-radius..=radius).map(move |dx| (dx, dy)))
+        .map(|(dx, dy)| gaussian(((dx * dx + dy * dy) as f64).sqrt(), spatial_sigma))
+        .collect();
+    let range: Vec<f64> = (0..=u8::MAX)
+        .map(|diff| gaussian(diff as f64, range_sigma))
+        .collect();
+
+    let mut res = img.clone();
+
+    for y in 0..height {
+        if !progress() {
+            return None;
+        }
+
+        for x in 0..width {
+            let [tone, alpha] = img.get_pixel(x, y).0;
+            if alpha == 0 {
+                continue;
+            }
+
+            let mut sum = 0.0;
+            let mut total = 0.0;
+
+            for dy in -radius..=radius {
+                let n_y = y as i64 + dy;
+                if n_y < 0 || n_y >= height as i64 {
+                    continue;
+                }
+
+                for dx in -radius..=radius {
+                    let n_x = x as i64 + dx;
+                    if n_x < 0 || n_x >= width as i64 {
+                        continue;
+                    }
+
+                    let [n_tone, n_alpha] = img.get_pixel(n_x as u32, n_y as u32).0;
+                    let offset = (dy + radius) as usize * side + (dx + radius) as usize;
+                    let weight = spatial[offset]
+                        * range[n_tone.abs_diff(tone) as usize]
+                        * (n_alpha as f64 / u8::MAX as f64);
+
+                    sum += n_tone as f64 * weight;
+                    total += weight;
+                }
+            }
+
+            res.put_pixel(x, y, LumaA([(sum / total).round() as u8, alpha]));
+        }
+    }
+
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayAlphaImage, LumaA};
+
+    use super::{bilateral, bilateral_with_progress, BilateralParams};
+
+    /// Mean squared difference with the tone of the side of each pixel
+    fn noise(img: &GrayAlphaImage) -> f64 {
+        img.enumerate_pixels()
+            .map(|(x, _, p)| (p[0] as f64 - if x < 20 { 50.0 } else { 200.0 }).powi(2))
+            .sum::<f64>()
+            / img.len() as f64
+    }
+
+    #[test]
+    fn keeps_edges() {
+        let img = GrayAlphaImage::from_fn(40, 20, |x, y| {
+            let noise = ((x * 7 + y * 13) % 11) as u8;
+            LumaA([if x < 20 { 45 + noise } else { 195 + noise }, 255])
+        });
+
+        let smoothed = bilateral(&img, &BilateralParams::default());
+
+        assert!(noise(&smoothed) < noise(&img) / 4.0);
+        for y in 0..20 {
+            assert!(smoothed.get_pixel(19, y)[0] <= 56);
+            assert!(smoothed.get_pixel(20, y)[0] >= 195);
+        }
+    }
+
+    #[test]
+    fn transparency() {
+        let flat = GrayAlphaImage::from_pixel(9, 9, LumaA([80, 255]));
+        assert_eq!(bilateral(&flat, &BilateralParams::default()), flat);
+
+        // A hidden light pixel in the middle does not brighten its neighbours
+        let mut img = flat.clone();
+        img.put_pixel(4, 4, LumaA([90, 0]));
+        let smoothed = bilateral(&img, &BilateralParams::default());
+
+        assert_eq!(*smoothed.get_pixel(4, 4), LumaA([90, 0]));
+        assert!(smoothed
+            .enumerate_pixels()
+            .all(|(x, y, p)| (x, y) == (4, 4) || p[0] == 80));
+    }
+
+    #[test]
+    fn stopped() {
+        let img = GrayAlphaImage::from_fn(8, 6, |x, _| LumaA([x as u8 * 30, 255]));
+        let params = BilateralParams::default();
+
+        let mut rows = 0;
+        let res = bilateral_with_progress(&img, &params, || {
+            rows += 1;
+            rows < 3
+        });
+        assert!(res.is_none());
+        assert_eq!(rows, 3);
+
+        let mut rows = 0;
+        let res = bilateral_with_progress(&img, &params, || {
+            rows += 1;
+            true
+        });
+        assert_eq!(res, Some(bilateral(&img, &params)));
+        assert_eq!(rows, 6);
+    }
+
+    #[test]
+    fn wide_spreads() {
+        let img = GrayAlphaImage::from_fn(6, 4, |x, _| LumaA([x as u8 * 40, 255]));
+
+        for sigma in [1e6, f64::INFINITY, f64::MAX] {
+            let params = BilateralParams {
+                spatial_sigma: sigma,
+                range_sigma: sigma,
+            };
+            let smoothed = bilateral(&img, &params);
+
+            // Every pixel takes about the mean tone
+            assert!(smoothed.pixels().all(|p| p[0].abs_diff(100) <= 1));
+        }
+    }
+}
@@ -1,23 +1,30 @@
+pub mod area;
 pub mod centroid;
 pub mod connection;
+pub mod criterion;
+pub mod label_map;
+pub mod merging;
 pub mod overlapping;
+pub mod slic;
 
-use std::{collections::HashMap, ops::RangeInclusive};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{self, Display, Formatter},
+    ops::RangeInclusive,
+};
 
 use image::GrayAlphaImage;
 
-use crate::{
-    graphs::SegmentGraph,
-    helpers::{
-        Centroid, Connected, Coordinates, CoordinatesF, Crop, Overlaps, SameTone, SmallCoord,
-        Transparent,
-    },
-};
+use crate::helpers::{Centroid, Coordinates, CoordinatesF, Crop, Overlaps, Transparent};
+
+use self::criterion::{Criterion, ExactTone};
 
 /// Keys -> y coordinates
 /// Values -> ranges of the x values that belongs in the y coordinate
-pub type Segment = HashMap<u16, Vec<RangeInclusive<u16>>>;
+pub type Segment = HashMap<u32, Vec<RangeInclusive<u32>>>;
 
+/// Connected pixels sharing a tone
 #[derive(Default)]
 pub struct GeoSegment {
     pub centroid: CoordinatesF,
@@ -36,40 +43,144 @@ impl Crop for ImageSegments {
     }
 }
 
+/// Most segments an image can be split into, so that each one has a label in a [`LabelMap`]
+///
+/// [`LabelMap`]: label_map::LabelMap
+pub const MAX_SEGMENTS: usize = u32::MAX as usize;
+
+#[derive(Debug)]
+pub enum SegmentationError {
+    /// The image splits into more segments than the limit, [`MAX_SEGMENTS`] unless lowered
+    TooManySegments(usize),
+}
+
+impl Display for SegmentationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SegmentationError::TooManySegments(limit) => {
+                write!(f, "image has more than {limit} segments")
+            }
+        }
+    }
+}
+
+impl Error for SegmentationError {}
+
+/// Which neighbours of a pixel belong to its segment, and which segments touch each other
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Connectivity {
+    /// Sides only
+    #[default]
+    Four,
+    /// Sides and corners
+    Eight,
+}
+
+impl Connectivity {
+    /// How far past its ends a range reaches into the rows above and below it
+    #[inline]
+    pub fn reach(&self) -> u32 {
+        match self {
+            Connectivity::Four => 0,
+            Connectivity::Eight => 1,
+        }
+    }
+}
+
 enum Direction {
     Left,
     Right,
 }
 
-pub struct ImgSegmentation<'a> {
+pub struct ImgSegmentation<'a, C = ExactTone<'a>> {
     pub visited: VisitedPixels,
     pub segments: ImageSegments,
     img: &'a GrayAlphaImage,
+    criterion: C,
+    connectivity: Connectivity,
 }
 
 impl<'a> ImgSegmentation<'a> {
-    pub fn segment_img(img: &'a GrayAlphaImage) -> ImageSegments {
+    /// Splits the visible pixels into segments of connected pixels with the same tone
+    pub fn segment_img(
+        img: &'a GrayAlphaImage,
+        connectivity: Connectivity,
+    ) -> Result<ImageSegments, SegmentationError> {
+        Self::segment_with(img, ExactTone::new(img), connectivity)
+    }
+}
+
+/// Splits the visible pixels of an image into connected segments, with their tones and centroids
+/// filled in
+pub trait Segmenter<'a> {
+    type Image;
+    /// Settings of the segmentation, the connectivity of the segments included
+    type Params;
+
+    fn segment(img: &'a Self::Image, params: Self::Params)
+        -> Result<ImageSegments, SegmentationError>;
+}
+
+impl<'a> Segmenter<'a> for ImgSegmentation<'a> {
+    type Image = GrayAlphaImage;
+    type Params = Connectivity;
+
+    fn segment(
+        img: &'a GrayAlphaImage,
+        connectivity: Connectivity,
+    ) -> Result<ImageSegments, SegmentationError> {
+        Self::segment_img(img, connectivity)
+    }
+}
+
+impl<'a, C> ImgSegmentation<'a, C>
+where
+    C: Criterion,
+{
+    /// Splits the visible pixels of `img` into segments grown while `criterion` accepts the
+    /// pixels around them
+    pub fn segment_with(
+        img: &'a GrayAlphaImage,
+        criterion: C,
+        connectivity: Connectivity,
+    ) -> Result<ImageSegments, SegmentationError> {
+        Self::segment_with_limit(img, criterion, connectivity, MAX_SEGMENTS)
+    }
+
+    /// Same as [`ImgSegmentation::segment_with`], giving up once there would be more than
+    /// `limit` segments. Limits above [`MAX_SEGMENTS`] are lowered to it.
+    pub fn segment_with_limit(
+        img: &'a GrayAlphaImage,
+        criterion: C,
+        connectivity: Connectivity,
+        limit: usize,
+    ) -> Result<ImageSegments, SegmentationError> {
+        let limit = limit.min(MAX_SEGMENTS);
         let segments = ImageSegments::new();
-        let visited = VisitedPixels::new((img.width() as u16, img.height() as u16));
+        let visited = VisitedPixels::new(img.dimensions());
 
         let mut this = Self {
             visited,
             segments,
             img,
+            criterion,
+            connectivity,
         };
 
         for (x, y, _) in this.img.enumerate_pixels() {
-            let coords = (x as u16, y as u16);
+            let coords = (x, y);
 
             if !this.visited.is_visited(coords) {
                 this.visited.visit_tone(coords);
 
                 if !this.img.is_transparent(coords) {
-                    let mut new_segment = GeoSegment {
-                        tone: this.img.get_pixel_s(coords)[0],
-                        ..GeoSegment::default()
-                    };
+                    if this.segments.len() == limit {
+                        return Err(SegmentationError::TooManySegments(limit));
+                    }
+
+                    let mut new_segment = GeoSegment::default();
                     this.mount_segment(&mut new_segment, coords);
+                    new_segment.tone = this.criterion.tone();
                     new_segment.centroid = new_segment.seg.calc_centroid(this.img);
 
                     this.segments.push(new_segment);
@@ -77,55 +188,79 @@ impl<'a> ImgSegmentation<'a> {
             }
         }
 
-        this.segments
+        Ok(this.segments)
     }
 
+    /// Scanline fill: each line found queues the rows above and below it, which are scanned in
+    /// the same order as a depth-first recursion, without growing the call stack
     fn mount_segment(&mut self, new_segment: &mut GeoSegment, coords: Coordinates) {
+        self.criterion.seed(coords);
+        self.criterion.add(coords);
+
+        // Rows -> x values still to be scanned in them, the last one goes first
+        let mut pending: Vec<(u32, RangeInclusive<u32>)> = Vec::new();
+        self.mount_row(new_segment, coords, &mut pending);
+
+        while let Some((y, xs)) = pending.last_mut() {
+            let Some(x) = xs.next() else {
+                pending.pop();
+                continue;
+            };
+            let coords = (x, *y);
+
+            if self.joins(coords) {
+                self.visited.visit_tone(coords);
+                self.criterion.add(coords);
+                self.mount_row(new_segment, coords, &mut pending);
+            }
+        }
+    }
+
+    fn mount_row(
+        &mut self,
+        new_segment: &mut GeoSegment,
+        coords: Coordinates,
+        pending: &mut Vec<(u32, RangeInclusive<u32>)>,
+    ) {
         let tone_range = self.mount_line(coords);
-        let tone = self.img.get_pixel_s(coords)[0];
 
         new_segment
             .seg
             .entry(coords.1)
-            .and_modify(|v| v.push(tone_range.clone()))
-            .or_insert_with(|| vec![tone_range.clone()]);
+            .or_default()
+            .push(tone_range.clone());
 
-        if coords.1 > 0 {
-            for x in tone_range.clone() {
-                self.mount_next_line((x, coords.1 - 1), tone, new_segment);
-            }
-        }
-        if (coords.1 as u32) < self.img.height() - 1 {
-            for x in tone_range {
-                self.mount_next_line((x, coords.1 + 1), tone, new_segment);
-            }
-        }
-    }
+        let reach = self.connectivity.reach();
+        let next_xs = tone_range.start().saturating_sub(reach)
+            ..=(tone_range.end() + reach).min(self.img.width() - 1);
 
-    fn mount_next_line(&mut self, coords: (u16, u16), tone: u8, new_segment: &mut GeoSegment) {
-        if !self.visited.is_visited(coords)
-            && self.img.same_tone(coords, tone)
-            && !self.img.is_transparent(coords)
-        {
-            self.visited.visit_tone(coords);
-            self.mount_segment(new_segment, coords);
+        if coords.1 < self.img.height() - 1 {
+            pending.push((coords.1 + 1, next_xs.clone()));
+        }
+        if coords.1 > 0 {
+            pending.push((coords.1 - 1, next_xs));
         }
     }
 
     #[inline]
-    fn mount_line(&mut self, coords: Coordinates) -> RangeInclusive<u16> {
+    fn mount_line(&mut self, coords: Coordinates) -> RangeInclusive<u32> {
         let lower = self.side_scan(coords, Direction::Left);
         let upper = self.side_scan(coords, Direction::Right);
 
         lower..=upper
     }
 
-    fn side_scan(&mut self, coords: Coordinates, direction: Direction) -> u16 {
-        let tone = self.img.get_pixel_s(coords)[0];
+    #[inline]
+    fn joins(&self, coords: Coordinates) -> bool {
+        !self.visited.is_visited(coords)
+            && !self.img.is_transparent(coords)
+            && self.criterion.accepts(coords)
+    }
 
+    fn side_scan(&mut self, coords: Coordinates, direction: Direction) -> u32 {
         let walk = match direction {
             Direction::Left => Box::from_iter((0..coords.0).rev()),
-            Direction::Right => Box::from_iter((coords.0 + 1)..self.img.width() as u16),
+            Direction::Right => Box::from_iter((coords.0 + 1)..self.img.width()),
         };
 
         let mut res = coords.0;
@@ -133,8 +268,9 @@ impl<'a> ImgSegmentation<'a> {
         for x in walk.iter() {
             let coords: Coordinates = (*x, coords.1);
 
-            if self.img.same_tone(coords, tone) && !self.img.is_transparent(coords) {
+            if self.joins(coords) {
                 self.visited.visit_tone(coords);
+                self.criterion.add(coords);
                 res = *x;
             } else {
                 break;
@@ -170,25 +306,92 @@ impl VisitedPixels {
 
 #[cfg(test)]
 mod tests {
-    use image::io::Reader;
+    use std::path::Path;
 
-    use crate::helpers::{img_to_segs, Connected, Crop, Overlaps};
+    use image::{io::Reader, GrayAlphaImage, LumaA};
 
-    use super::{ImgSegmentation, Segment};
+    use crate::helpers::{img_to_segs, Boundary, Connected, Crop, Overlaps};
+
+    use super::{criterion::ExactTone, Connectivity, ImgSegmentation, Segment, SegmentationError};
 
     #[test]
     fn segmentation() {
-        let img = Reader::open(r"img_segments\segments.tif")
+        let img = Reader::open(Path::new("img_segments").join("segments.tif"))
             .unwrap()
             .decode()
             .unwrap()
             .to_luma_alpha8();
 
-        let segments = ImgSegmentation::segment_img(&img);
+        let segments = ImgSegmentation::segment_img(&img, Connectivity::Four).unwrap();
 
         assert_eq!(segments.len(), 8);
     }
 
+    #[test]
+    fn diagonal_segmentation() {
+        // A stroke going down and to the right, then down and to the left
+        let img = GrayAlphaImage::from_fn(6, 8, |x, y| {
+            let on_stroke = if y < 4 { x == y } else { x == 7 - y };
+            LumaA([90, if on_stroke { 255 } else { 0 }])
+        });
+
+        let four = ImgSegmentation::segment_img(&img, Connectivity::Four).unwrap();
+        let eight = ImgSegmentation::segment_img(&img, Connectivity::Eight).unwrap();
+
+        assert_eq!(four.len(), 7);
+        assert_eq!(eight.len(), 1);
+        assert_eq!(eight[0].seg.len(), 8);
+    }
+
+    #[test]
+    fn segment_limit() {
+        // Columns of alternating tones, one segment each
+        let img = GrayAlphaImage::from_fn(6, 3, |x, _| LumaA([x as u8 % 2 * 100, 255]));
+        let segment = |limit| {
+            ImgSegmentation::segment_with_limit(
+                &img,
+                ExactTone::new(&img),
+                Connectivity::Four,
+                limit,
+            )
+        };
+
+        assert_eq!(segment(6).unwrap().len(), 6);
+
+        let Err(err) = segment(5) else {
+            panic!("expected too many segments");
+        };
+        assert!(matches!(err, SegmentationError::TooManySegments(5)));
+        assert_eq!(err.to_string(), "image has more than 5 segments");
+    }
+
+    #[test]
+    fn large_segment() {
+        let img = GrayAlphaImage::from_pixel(4000, 4000, LumaA([128, 255]));
+
+        let segments = ImgSegmentation::segment_img(&img, Connectivity::Four).unwrap();
+
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].seg.len(), 4000);
+        assert!(segments[0]
+            .seg
+            .values()
+            .all(|ranges| ranges == &vec![0..=3999]));
+    }
+
+    #[test]
+    fn wide_image() {
+        let img = GrayAlphaImage::from_fn(70000, 2, |x, _| {
+            LumaA([if x < 66000 { 40 } else { 90 }, 255])
+        });
+
+        let segments = ImgSegmentation::segment_img(&img, Connectivity::Four).unwrap();
+
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].seg[&1], vec![0..=65999]);
+        assert_eq!(segments[1].seg[&1], vec![66000..=69999]);
+    }
+
     #[test]
     fn overlapping() {
         let ranges_1 = vec![0..=5, 10..=15];
@@ -202,7 +405,7 @@ mod tests {
         let seg_1 = Segment::from([(0, vec![0..=5]), (1, vec![0..=5])]);
         let seg_2 = Segment::from([(0, vec![6..=8])]);
 
-        assert!(seg_1.is_connected(&seg_2));
+        assert!(seg_1.is_connected(&seg_2, Connectivity::Four));
     }
 
     #[test]
@@ -210,13 +413,59 @@ mod tests {
         let seg_1 = Segment::from([(0, vec![0..=5]), (1, vec![0..=5])]);
         let seg_2 = Segment::from([(2, vec![3..=3])]);
 
-        assert!(seg_1.is_connected(&seg_2));
+        assert!(seg_1.is_connected(&seg_2, Connectivity::Four));
+    }
+
+    #[test]
+    fn interior_connection() {
+        // A cup, open on its bottom, with a segment inside touching only the row under its top
+        let cup = Segment::from([
+            (0, vec![0..=6]),
+            (1, vec![0..=0, 6..=6]),
+            (2, vec![0..=0, 6..=6]),
+            (3, vec![0..=0, 6..=6]),
+        ]);
+        let inside = Segment::from([(2, vec![3..=3])]);
+
+        assert!(!cup.is_connected(&inside, Connectivity::Four));
+        assert!(!inside.is_connected(&cup, Connectivity::Four));
+
+        // Under the top row, but in no row shared with the cup's bottom
+        let hanging = Segment::from([(1, vec![2..=4]), (2, vec![3..=3])]);
+
+        assert!(cup.is_connected(&hanging, Connectivity::Four));
+        assert!(hanging.is_connected(&cup, Connectivity::Four));
+    }
+
+    #[test]
+    fn boundary_length() {
+        let seg_1 = Segment::from([(0, vec![0..=5]), (1, vec![0..=5])]);
+        let seg_2 = Segment::from([(0, vec![6..=8]), (2, vec![3..=9])]);
+
+        // One pixel to the side on row 0, three below row 1
+        assert_eq!(seg_1.boundary_length(&seg_2, Connectivity::Four), 4);
+        assert_eq!(seg_2.boundary_length(&seg_1, Connectivity::Four), 4);
+
+        // Plus the corners: (5, 1) with (6, 0), and (2..=6, 2) diagonal to row 1
+        assert_eq!(seg_1.boundary_length(&seg_2, Connectivity::Eight), 11);
+        assert_eq!(seg_2.boundary_length(&seg_1, Connectivity::Eight), 11);
+    }
+
+    #[test]
+    fn diagonal_connection() {
+        let seg_1 = Segment::from([(0, vec![0..=2])]);
+        let seg_2 = Segment::from([(1, vec![3..=4])]);
+
+        assert!(!seg_1.is_connected(&seg_2, Connectivity::Four));
+        assert!(seg_1.is_connected(&seg_2, Connectivity::Eight));
+        assert!(seg_2.is_connected(&seg_1, Connectivity::Eight));
+        assert_eq!(seg_1.boundary_length(&seg_2, Connectivity::Eight), 1);
     }
 
     #[test]
     fn cropping() {
-        let seg_1 = img_to_segs(r"img_segments\crop_1.png");
-        let seg_2 = img_to_segs(r"img_segments\crop_2.png");
+        let seg_1 = img_to_segs(Path::new("img_segments").join("crop_1.png"));
+        let seg_2 = img_to_segs(Path::new("img_segments").join("crop_2.png"));
 
         let seg_2 = seg_1.crop(seg_2);
 
@@ -0,0 +1,12 @@
+use crate::helpers::Area;
+
+use super::Segment;
+
+impl Area for Segment {
+    fn area(&self) -> usize {
+        self.values()
+            .flat_map(|ranges| ranges.iter())
+            .map(|range| range.clone().count())
+            .sum()
+    }
+}
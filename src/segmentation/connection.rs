@@ -1,10 +1,11 @@
-use crate::helpers::{Connected, Overlaps};
-use crate::segmentation::Segment;
+use crate::helpers::{Boundary, Connected};
+use crate::segmentation::{Connectivity, Segment};
 use std::ops::RangeInclusive;
 
-impl Connected for RangeInclusive<u16> {
+/// Ranges in the same row touch side by side, whatever the connectivity
+impl Connected for RangeInclusive<u32> {
     #[inline]
-    fn is_connected(&self, other: &Self) -> bool {
+    fn is_connected(&self, other: &Self, _: Connectivity) -> bool {
         if self.end() + 1 == *other.start() {
             true
         } else {
@@ -13,45 +14,87 @@ impl Connected for RangeInclusive<u16> {
     }
 }
 
-impl Connected for Vec<RangeInclusive<u16>> {
+impl Connected for Vec<RangeInclusive<u32>> {
     #[inline]
-    fn is_connected(&self, other: &Self) -> bool {
+    fn is_connected(&self, other: &Self, connectivity: Connectivity) -> bool {
         self.iter().any(|range| {
             other
                 .iter()
-                .any(|other_range| range.is_connected(other_range))
+                .any(|other_range| range.is_connected(other_range, connectivity))
         })
     }
 }
 
+/// Pixels of `range` with a pixel of `other` `shift` columns to their right
+#[inline]
+fn shifted_overlap(range: &RangeInclusive<u32>, other: &RangeInclusive<u32>, shift: i64) -> usize {
+    let start = (*range.start() as i64).max(*other.start() as i64 - shift);
+    let end = (*range.end() as i64).min(*other.end() as i64 - shift);
+
+    (end - start + 1).max(0) as usize
+}
+
+/// Pairs of neighbouring pixels between ranges of adjacent rows
+#[inline]
+fn vertical_pairs(
+    range: &RangeInclusive<u32>,
+    other: &RangeInclusive<u32>,
+    connectivity: Connectivity,
+) -> usize {
+    let reach = connectivity.reach() as i64;
+
+    (-reach..=reach)
+        .map(|shift| shifted_overlap(range, other, shift))
+        .sum()
+}
+
+/// Ranges of `other` in the rows above and below `y`
+fn adjacent_rows(other: &Segment, y: u32) -> impl Iterator<Item = &Vec<RangeInclusive<u32>>> {
+    [y.checked_sub(1), y.checked_add(1)]
+        .into_iter()
+        .flatten()
+        .filter_map(|row| other.get(&row))
+}
+
 impl Connected for Segment {
-    fn is_connected(&self, other: &Self) -> bool {
-        let common_ys = self.iter().filter(|(y, _)| other.contains_key(y));
-
-        let mut res = common_ys.clone().any(|(y, ranges)| {
-            let other_ranges = other.get(y).unwrap();
-            ranges.is_connected(other_ranges)
-        });
-
-        if !res {
-            let (min_row, ranges) = self.iter().min_by_key(|(y, _)| **y).unwrap();
-
-            // Necessário para não diminuir a coordenada 0
-            if let Some(upper_row) = min_row.checked_sub(1) {
-                if let Some(other_ranges) = other.get(&upper_row) {
-                    res = ranges.overlaps(other_ranges);
-                }
-            }
-        }
+    fn is_connected(&self, other: &Self, connectivity: Connectivity) -> bool {
+        self.iter().any(|(y, ranges)| {
+            let side = other
+                .get(y)
+                .is_some_and(|other_ranges| ranges.is_connected(other_ranges, connectivity));
 
-        if !res {
-            let (max_row, ranges) = self.iter().max_by_key(|(y, _)| **y).unwrap();
+            side || adjacent_rows(other, *y).any(|other_ranges| {
+                ranges.iter().any(|range| {
+                    other_ranges
+                        .iter()
+                        .any(|o| vertical_pairs(range, o, connectivity) > 0)
+                })
+            })
+        })
+    }
+}
 
-            if let Some(other_ranges) = other.get(&(max_row + 1)) {
-                res = ranges.overlaps(other_ranges);
-            }
-        }
+impl Boundary for Segment {
+    fn boundary_length(&self, other: &Self, connectivity: Connectivity) -> usize {
+        self.iter().fold(0, |acc, (y, ranges)| {
+            let side = other.get(y).map_or(0, |other_ranges| {
+                ranges
+                    .iter()
+                    .flat_map(|range| other_ranges.iter().map(move |o| (range, o)))
+                    .filter(|(range, o)| range.is_connected(o, connectivity))
+                    .count()
+            });
+
+            let vertical = adjacent_rows(other, *y)
+                .flat_map(|other_ranges| {
+                    ranges
+                        .iter()
+                        .flat_map(move |range| other_ranges.iter().map(move |o| (range, o)))
+                })
+                .map(|(range, o)| vertical_pairs(range, o, connectivity))
+                .sum::<usize>();
 
-        res
+            acc + side + vertical
+        })
     }
 }
@@ -0,0 +1,306 @@
+use image::{DynamicImage, GrayAlphaImage, Pixel, RgbaImage};
+
+use crate::{
+    colour::{distance, Colour, ColourSpace},
+    helpers::{Coordinates, SameTone, SmallCoord},
+};
+
+use super::{Connectivity, ImageSegments, ImgSegmentation, SegmentationError};
+
+/// What makes pixels part of the same segment
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum SegmentationMode {
+    /// Exactly the same tone, after quantizing the luminance
+    #[default]
+    Tone,
+    /// Colours within `threshold` of the colour the segment started from
+    Colour { space: ColourSpace, threshold: f64 },
+    /// Tones within `tolerance` of the reference, without quantizing the luminance
+    Tolerance { tolerance: u8, reference: Reference },
+    /// SLIC superpixels starting from square cells of `size` pixels
+    Superpixels { size: u32, compactness: f64 },
+}
+
+/// What a pixel is compared with to join a segment
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Reference {
+    /// The pixel the segment started from
+    #[default]
+    Seed,
+    /// The mean of the pixels in the segment so far, which lets it follow slow gradients
+    Mean,
+}
+
+/// Decides which visible pixels join the segment being grown
+pub trait Criterion {
+    /// Starts a new segment at a pixel
+    fn seed(&mut self, coords: Coordinates);
+
+    /// Whether a pixel next to the segment belongs in it
+    fn accepts(&self, coords: Coordinates) -> bool;
+
+    /// Called for every pixel that joins the segment, the seed included
+    fn add(&mut self, _coords: Coordinates) {}
+
+    /// Tone of the finished segment
+    fn tone(&self) -> u8;
+}
+
+/// Pixels with exactly the tone of the seed, meant for quantized images
+pub struct ExactTone<'a> {
+    img: &'a GrayAlphaImage,
+    tone: u8,
+}
+
+impl<'a> ExactTone<'a> {
+    pub fn new(img: &'a GrayAlphaImage) -> Self {
+        Self { img, tone: 0 }
+    }
+}
+
+impl Criterion for ExactTone<'_> {
+    #[inline]
+    fn seed(&mut self, coords: Coordinates) {
+        self.tone = self.img.get_pixel_s(coords)[0];
+    }
+
+    #[inline]
+    fn accepts(&self, coords: Coordinates) -> bool {
+        self.img.same_tone(coords, self.tone)
+    }
+
+    #[inline]
+    fn tone(&self) -> u8 {
+        self.tone
+    }
+}
+
+/// Pixels whose tone is within a tolerance of the seed's or of the segment's mean
+pub struct ToneTolerance<'a> {
+    img: &'a GrayAlphaImage,
+    tolerance: u8,
+    reference: Reference,
+    seed: u8,
+    sum: u64,
+    count: u64,
+}
+
+impl<'a> ToneTolerance<'a> {
+    pub fn new(img: &'a GrayAlphaImage, tolerance: u8, reference: Reference) -> Self {
+        Self {
+            img,
+            tolerance,
+            reference,
+            seed: 0,
+            sum: 0,
+            count: 0,
+        }
+    }
+
+    #[inline]
+    fn mean(&self) -> f64 {
+        self.sum as f64 / self.count.max(1) as f64
+    }
+}
+
+impl Criterion for ToneTolerance<'_> {
+    #[inline]
+    fn seed(&mut self, coords: Coordinates) {
+        self.seed = self.img.get_pixel_s(coords)[0];
+        self.sum = 0;
+        self.count = 0;
+    }
+
+    #[inline]
+    fn accepts(&self, coords: Coordinates) -> bool {
+        let tone = self.img.get_pixel_s(coords)[0];
+
+        match self.reference {
+            Reference::Seed => tone.abs_diff(self.seed) <= self.tolerance,
+            Reference::Mean => (tone as f64 - self.mean()).abs() <= self.tolerance as f64,
+        }
+    }
+
+    #[inline]
+    fn add(&mut self, coords: Coordinates) {
+        self.sum += self.img.get_pixel_s(coords)[0] as u64;
+        self.count += 1;
+    }
+
+    /// Mean tone of the segment
+    #[inline]
+    fn tone(&self) -> u8 {
+        self.mean().round() as u8
+    }
+}
+
+/// Pixels whose colour is within a distance of the seed's colour
+pub struct ColourDistance {
+    colours: Vec<Colour>,
+    tones: Vec<u8>,
+    width: usize,
+    threshold: f64,
+    seed: usize,
+}
+
+impl ColourDistance {
+    pub fn new(img: &RgbaImage, space: ColourSpace, threshold: f64) -> Self {
+        Self {
+            colours: img.pixels().map(|p| space.from_rgb(p.to_rgb().0)).collect(),
+            tones: img.pixels().map(|p| p.to_luma()[0]).collect(),
+            width: img.width() as usize,
+            threshold,
+            seed: 0,
+        }
+    }
+
+    #[inline]
+    fn index(&self, coords: Coordinates) -> usize {
+        coords.0 as usize + coords.1 as usize * self.width
+    }
+}
+
+impl Criterion for ColourDistance {
+    #[inline]
+    fn seed(&mut self, coords: Coordinates) {
+        self.seed = self.index(coords);
+    }
+
+    #[inline]
+    fn accepts(&self, coords: Coordinates) -> bool {
+        let colour = &self.colours[self.index(coords)];
+        distance(colour, &self.colours[self.seed]) <= self.threshold
+    }
+
+    #[inline]
+    fn tone(&self) -> u8 {
+        self.tones[self.seed]
+    }
+}
+
+impl<'a> ImgSegmentation<'a, ToneTolerance<'a>> {
+    /// Splits the visible pixels into segments of connected pixels within `tolerance` of their
+    /// segment's reference tone
+    pub fn segment_img_tolerance(
+        img: &'a GrayAlphaImage,
+        tolerance: u8,
+        reference: Reference,
+        connectivity: Connectivity,
+    ) -> Result<ImageSegments, SegmentationError> {
+        Self::segment_with(
+            img,
+            ToneTolerance::new(img, tolerance, reference),
+            connectivity,
+        )
+    }
+}
+
+impl<'a> ImgSegmentation<'a, ColourDistance> {
+    /// Splits the visible pixels into segments of connected pixels within `threshold` of the
+    /// colour their segment started from, measured in `space`
+    pub fn segment_colour_img(
+        img: &RgbaImage,
+        space: ColourSpace,
+        threshold: f64,
+        connectivity: Connectivity,
+    ) -> Result<ImageSegments, SegmentationError> {
+        let luma = DynamicImage::ImageRgba8(img.clone()).to_luma_alpha8();
+        let criterion = ColourDistance::new(img, space, threshold);
+
+        ImgSegmentation::segment_with(&luma, criterion, connectivity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayAlphaImage, LumaA, Rgba, RgbaImage};
+
+    use crate::{
+        colour::ColourSpace,
+        segmentation::{Connectivity, ImgSegmentation},
+    };
+
+    use super::Reference;
+
+    /// Red and green halves of about the same luminance, with some noise
+    fn image() -> RgbaImage {
+        RgbaImage::from_fn(20, 10, |x, y| {
+            let noise = ((x * 7 + y * 3) % 5) as u8;
+
+            if x < 10 {
+                Rgba([240 + noise, 40, 40, 255])
+            } else {
+                Rgba([40, 100 + noise, 40, 255])
+            }
+        })
+    }
+
+    #[test]
+    fn same_luminance() {
+        let img = image();
+
+        for space in [ColourSpace::Rgb, ColourSpace::Lab] {
+            let segments =
+                ImgSegmentation::segment_colour_img(&img, space, 12.0, Connectivity::Four).unwrap();
+
+            assert_eq!(segments.len(), 2);
+            assert!(segments[0]
+                .seg
+                .values()
+                .all(|ranges| ranges == &vec![0..=9]));
+            assert!(segments[1]
+                .seg
+                .values()
+                .all(|ranges| ranges == &vec![10..=19]));
+            assert!(segments[0].tone.abs_diff(segments[1].tone) < 10);
+        }
+    }
+
+    /// Two noisy regions, the left one brightening slowly from left to right
+    fn noisy_luma() -> GrayAlphaImage {
+        GrayAlphaImage::from_fn(40, 10, |x, y| {
+            let noise = ((x * 7 + y * 13) % 5) as u8;
+
+            if x < 30 {
+                LumaA([40 + x as u8 / 3 + noise, 255])
+            } else {
+                LumaA([180 + noise, 255])
+            }
+        })
+    }
+
+    #[test]
+    fn tolerance() {
+        let img = noisy_luma();
+
+        let exact = ImgSegmentation::segment_img(&img, Connectivity::Four).unwrap();
+        let seed =
+            ImgSegmentation::segment_img_tolerance(&img, 8, Reference::Seed, Connectivity::Four)
+                .unwrap();
+        let mean =
+            ImgSegmentation::segment_img_tolerance(&img, 8, Reference::Mean, Connectivity::Four)
+                .unwrap();
+
+        assert!(exact.len() > 40);
+        // The gradient drifts away from the seed, but not from the mean following it
+        assert!(seed.len() > 2);
+        assert_eq!(mean.len(), 2);
+        assert!(mean[1].seg.values().all(|ranges| ranges == &vec![30..=39]));
+        assert!(mean[1].tone.abs_diff(182) <= 1);
+    }
+
+    #[test]
+    fn threshold() {
+        let img = image();
+
+        let strict =
+            ImgSegmentation::segment_colour_img(&img, ColourSpace::Rgb, 0.0, Connectivity::Four)
+                .unwrap();
+        let loose =
+            ImgSegmentation::segment_colour_img(&img, ColourSpace::Rgb, 500.0, Connectivity::Four)
+                .unwrap();
+
+        assert!(strict.len() > 2);
+        assert_eq!(loose.len(), 1);
+    }
+}
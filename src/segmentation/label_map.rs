@@ -0,0 +1,292 @@
+use std::{collections::BTreeMap, path::Path};
+
+use image::{GrayAlphaImage, ImageBuffer, ImageFormat, ImageResult, Luma, Rgba, RgbaImage};
+
+use crate::helpers::Centroid;
+
+use super::{Connectivity, GeoSegment, ImageSegments, ImgSegmentation, SegmentationError};
+
+/// Index of the segment of each pixel plus one, [`NO_LABEL`] where there is none
+pub type LabelMap = ImageBuffer<Luma<u32>, Vec<u32>>;
+
+/// Label of the pixels outside of every segment, like transparent ones
+pub const NO_LABEL: u32 = 0;
+
+impl<'a> ImgSegmentation<'a> {
+    /// Same as [`ImgSegmentation::segment_img`], along with the label map of the segments
+    pub fn segment_img_labeled(
+        img: &'a GrayAlphaImage,
+        connectivity: Connectivity,
+    ) -> Result<(ImageSegments, LabelMap), SegmentationError> {
+        let segments = Self::segment_img(img, connectivity)?;
+        let labels = to_label_map(&segments, img.dimensions());
+
+        Ok((segments, labels))
+    }
+}
+
+/// Pixels outside of the dimensions are left out
+pub fn to_label_map<'s, I>(segments: I, dimensions: (u32, u32)) -> LabelMap
+where
+    I: IntoIterator<Item = &'s GeoSegment>,
+{
+    let mut res = LabelMap::new(dimensions.0, dimensions.1);
+
+    for (i, segment) in segments.into_iter().enumerate() {
+        let label = i as u32 + 1;
+
+        for (y, ranges) in segment.seg.iter().filter(|(y, _)| **y < dimensions.1) {
+            for x in ranges.iter().flat_map(|range| range.clone()) {
+                if x < dimensions.0 {
+                    res.put_pixel(x, *y, Luma([label]));
+                }
+            }
+        }
+    }
+
+    res
+}
+
+/// Rebuilds the segments of a label map, taking their tones and centroids from `img`.
+/// Segments follow the order of their labels, which need not be consecutive.
+pub fn from_label_map(labels: &LabelMap, img: &GrayAlphaImage) -> ImageSegments {
+    // Label -> index of its segment
+    let mut indices: BTreeMap<u32, usize> = labels
+        .pixels()
+        .map(|p| p[0])
+        .filter(|label| *label != NO_LABEL)
+        .map(|label| (label, 0))
+        .collect();
+    for (i, index) in indices.values_mut().enumerate() {
+        *index = i;
+    }
+
+    let mut res: ImageSegments = (0..indices.len()).map(|_| GeoSegment::default()).collect();
+
+    for (y, row) in labels.enumerate_rows() {
+        let mut start = 0;
+        let mut row = row.map(|(x, _, p)| (x, p[0])).peekable();
+
+        while let Some((x, label)) = row.next() {
+            if row.peek().is_some_and(|(_, next)| *next == label) {
+                continue;
+            }
+
+            if label != NO_LABEL {
+                let segment = &mut res[indices[&label]];
+
+                if segment.seg.is_empty() {
+                    segment.tone = img.get_pixel(start, y)[0];
+                }
+                segment.seg.entry(y).or_default().push(start..=x);
+            }
+
+            start = x + 1;
+        }
+    }
+
+    for segment in res.iter_mut() {
+        segment.centroid = segment.seg.calc_centroid(img);
+    }
+
+    res
+}
+
+/// Smallest dimensions holding every pixel of the segments
+pub fn extent<'s, I>(segments: I) -> (u32, u32)
+where
+    I: IntoIterator<Item = &'s GeoSegment>,
+{
+    segments
+        .into_iter()
+        .flat_map(|segment| segment.seg.iter())
+        .fold((0, 0), |(width, height), (y, ranges)| {
+            let end = ranges
+                .iter()
+                .map(|range| *range.end() + 1)
+                .max()
+                .unwrap_or(0);
+            (width.max(end), height.max(y + 1))
+        })
+}
+
+/// Region adjacency in a single scan: pairs of touching labels, the smallest first, with the
+/// number of neighbouring pixels split between them
+pub fn adjacency(labels: &LabelMap, connectivity: Connectivity) -> BTreeMap<(u32, u32), usize> {
+    let (width, height) = labels.dimensions();
+    let reach = connectivity.reach() as i64;
+    let mut res = BTreeMap::new();
+
+    // Right, then the row below: each pair of neighbours is seen once
+    let offsets: Vec<(i64, i64)> = [(1, 0)]
+        .into_iter()
+        .chain((-reach..=reach).map(|dx| (dx, 1)))
+        .collect();
+
+    for (x, y, pixel) in labels.enumerate_pixels() {
+        let label = pixel[0];
+        if label == NO_LABEL {
+            continue;
+        }
+
+        for (dx, dy) in offsets.iter() {
+            let (n_x, n_y) = (x as i64 + dx, y as i64 + dy);
+            if n_x < 0 || n_x >= width as i64 || n_y >= height as i64 {
+                continue;
+            }
+
+            let other = labels.get_pixel(n_x as u32, n_y as u32)[0];
+            if other != NO_LABEL && other != label {
+                *res.entry((label.min(other), label.max(other))).or_insert(0) += 1;
+            }
+        }
+    }
+
+    res
+}
+
+/// Distinct colour for each label, transparent where there is no segment
+pub fn colorize(labels: &LabelMap) -> RgbaImage {
+    RgbaImage::from_fn(labels.width(), labels.height(), |x, y| {
+        let label = labels.get_pixel(x, y)[0];
+
+        if label == NO_LABEL {
+            return Rgba([0, 0, 0, 0]);
+        }
+
+        // Spreads consecutive labels over the whole colour space
+        let [r, g, b, _] = label.wrapping_mul(0x9E37_79B1).to_le_bytes();
+        Rgba([r, g, b, u8::MAX])
+    })
+}
+
+/// Saves the labels losslessly, each one packed in the four channels of a PNG pixel. The high
+/// byte is inverted in the alpha, so that maps with fewer than 2^24 labels stay opaque.
+pub fn save_label_map<P>(labels: &LabelMap, path: P) -> ImageResult<()>
+where
+    P: AsRef<Path>,
+{
+    let packed = RgbaImage::from_fn(labels.width(), labels.height(), |x, y| {
+        let [r, g, b, high] = labels.get_pixel(x, y)[0].to_le_bytes();
+        Rgba([r, g, b, u8::MAX - high])
+    });
+
+    packed.save_with_format(path, ImageFormat::Png)
+}
+
+/// Reads a label map written by [`save_label_map`]
+pub fn open_label_map<P>(path: P) -> ImageResult<LabelMap>
+where
+    P: AsRef<Path>,
+{
+    let packed = image::open(path)?.into_rgba8();
+
+    Ok(LabelMap::from_fn(
+        packed.width(),
+        packed.height(),
+        |x, y| {
+            let [r, g, b, alpha] = packed.get_pixel(x, y).0;
+            Luma([u32::from_le_bytes([r, g, b, u8::MAX - alpha])])
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayAlphaImage, Luma, LumaA};
+
+    use crate::segmentation::{Connectivity, ImgSegmentation, Segment};
+
+    use super::{colorize, from_label_map, open_label_map, save_label_map, LabelMap, NO_LABEL};
+
+    /// Sorted pixels of a segment, whatever the order of its ranges
+    fn pixels(segment: &Segment) -> Vec<(u32, u32)> {
+        let mut res: Vec<(u32, u32)> = segment
+            .iter()
+            .flat_map(|(y, ranges)| ranges.iter().flat_map(|r| r.clone().map(|x| (x, *y))))
+            .collect();
+        res.sort();
+
+        res
+    }
+
+    /// Stripes of three tones with a transparent hole
+    fn image() -> GrayAlphaImage {
+        GrayAlphaImage::from_fn(12, 9, |x, y| {
+            let alpha = if (4..7).contains(&x) && (3..5).contains(&y) {
+                0
+            } else {
+                255
+            };
+            LumaA([((x / 3 + y / 4) % 3) as u8 * 60, alpha])
+        })
+    }
+
+    #[test]
+    fn round_trip() {
+        let img = image();
+        let (segments, labels) =
+            ImgSegmentation::segment_img_labeled(&img, Connectivity::Four).unwrap();
+
+        assert_eq!(labels.get_pixel(5, 3)[0], NO_LABEL);
+
+        let rebuilt = from_label_map(&labels, &img);
+
+        assert_eq!(rebuilt.len(), segments.len());
+        for (segment, other) in segments.iter().zip(rebuilt.iter()) {
+            assert_eq!(pixels(&segment.seg), pixels(&other.seg));
+            assert_eq!(segment.tone, other.tone);
+            assert!((segment.centroid.0 - other.centroid.0).abs() < 1e-9);
+            assert!((segment.centroid.1 - other.centroid.1).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn sparse_labels() {
+        let labels = LabelMap::from_fn(4, 2, |x, _| match x {
+            0 => Luma([u32::MAX]),
+            1 => Luma([NO_LABEL]),
+            _ => Luma([7]),
+        });
+        let img = GrayAlphaImage::from_fn(4, 2, |x, _| LumaA([x as u8 * 10, 255]));
+
+        let segments = from_label_map(&labels, &img);
+
+        assert_eq!(segments.len(), 2);
+        assert_eq!(
+            pixels(&segments[0].seg),
+            vec![(2, 0), (2, 1), (3, 0), (3, 1)]
+        );
+        assert_eq!(segments[0].tone, 20);
+        assert_eq!(pixels(&segments[1].seg), vec![(0, 0), (0, 1)]);
+        assert_eq!(segments[1].tone, 0);
+    }
+
+    #[test]
+    fn saving() {
+        let img = image();
+        let (_, labels) = ImgSegmentation::segment_img_labeled(&img, Connectivity::Four).unwrap();
+        let path = std::env::temp_dir().join("camouflage_images_labels.png");
+
+        save_label_map(&labels, &path).unwrap();
+        let opened = open_label_map(&path).unwrap();
+        let packed = image::open(&path).unwrap().into_rgba8();
+
+        assert_eq!(opened, labels);
+        // Visible when opened as an image
+        assert!(packed.pixels().all(|p| p[3] == u8::MAX));
+
+        let mut labels = labels;
+        labels.put_pixel(0, 0, Luma([u32::MAX]));
+        labels.put_pixel(1, 0, Luma([0x0100_0000]));
+        save_label_map(&labels, &path).unwrap();
+        let opened = open_label_map(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(opened, labels);
+
+        let colors = colorize(&labels);
+        assert_eq!(colors.get_pixel(5, 3)[3], 0);
+        assert_ne!(colors.get_pixel(0, 0), colors.get_pixel(3, 0));
+    }
+}
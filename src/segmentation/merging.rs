@@ -0,0 +1,239 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap},
+};
+
+use image::{GrayAlphaImage, Luma};
+
+use crate::helpers::Area;
+
+use super::{
+    label_map::{adjacency, from_label_map, to_label_map, LabelMap, NO_LABEL},
+    Connectivity, ImageSegments,
+};
+
+/// Segment being merged, with the segments it was merged into
+struct Region {
+    /// Index of the region holding this one, itself while it is not merged
+    parent: usize,
+    area: usize,
+    tone_sum: f64,
+    /// Touching regions -> number of neighbouring pixels split between them
+    neighbours: BTreeMap<usize, usize>,
+}
+
+impl Region {
+    #[inline]
+    fn tone(&self) -> f64 {
+        self.tone_sum / self.area.max(1) as f64
+    }
+}
+
+/// Merges every segment with fewer than `min_area` pixels into the touching segment with the
+/// closest tone, the one sharing the longest border between equally close ones. The smallest
+/// segments go first, and merged segments get the mean tone of their pixels.
+///
+/// Segments without neighbours are kept whatever their area. `img` is the image the segments
+/// come from, used for their centroids.
+pub fn merge_small(
+    segments: ImageSegments,
+    img: &GrayAlphaImage,
+    min_area: usize,
+    connectivity: Connectivity,
+) -> ImageSegments {
+    let areas: Vec<usize> = segments.iter().map(|segment| segment.seg.area()).collect();
+    if areas.iter().all(|area| *area >= min_area) {
+        return segments;
+    }
+
+    let labels = to_label_map(&segments, img.dimensions());
+
+    let mut regions: Vec<Region> = segments
+        .iter()
+        .zip(areas)
+        .enumerate()
+        .map(|(i, (segment, area))| Region {
+            parent: i,
+            area,
+            tone_sum: segment.tone as f64 * area as f64,
+            neighbours: BTreeMap::new(),
+        })
+        .collect();
+
+    for ((a, b), length) in adjacency(&labels, connectivity) {
+        let (a, b) = (a as usize - 1, b as usize - 1);
+        regions[a].neighbours.insert(b, length);
+        regions[b].neighbours.insert(a, length);
+    }
+
+    let mut queue: BinaryHeap<Reverse<(usize, usize)>> = regions
+        .iter()
+        .enumerate()
+        .filter(|(_, region)| region.area < min_area)
+        .map(|(i, region)| Reverse((region.area, i)))
+        .collect();
+
+    while let Some(Reverse((area, i))) = queue.pop() {
+        // Left behind by a merge
+        if regions[i].parent != i || regions[i].area != area {
+            continue;
+        }
+
+        let tone = regions[i].tone();
+        let Some(target) = regions[i]
+            .neighbours
+            .iter()
+            .min_by(|(a, a_length), (b, b_length)| {
+                let a_diff = (regions[**a].tone() - tone).abs();
+                let b_diff = (regions[**b].tone() - tone).abs();
+
+                a_diff
+                    .total_cmp(&b_diff)
+                    .then(b_length.cmp(a_length))
+                    .then(a.cmp(b))
+            })
+            .map(|(target, _)| *target)
+        else {
+            continue;
+        };
+
+        merge(&mut regions, i, target);
+
+        if regions[target].area < min_area {
+            queue.push(Reverse((regions[target].area, target)));
+        }
+    }
+
+    // Merged regions take the label of the region holding them, in the order of the segments
+    let mut roots = vec![NO_LABEL; regions.len()];
+    let mut next = NO_LABEL;
+    for i in 0..regions.len() {
+        if regions[i].parent == i {
+            next += 1;
+            roots[i] = next;
+        }
+    }
+
+    let labels = LabelMap::from_fn(labels.width(), labels.height(), |x, y| {
+        match labels.get_pixel(x, y)[0] {
+            NO_LABEL => Luma([NO_LABEL]),
+            label => Luma([roots[find(&regions, label as usize - 1)]]),
+        }
+    });
+
+    let tones = regions
+        .iter()
+        .enumerate()
+        .filter(|(i, region)| region.parent == *i)
+        .map(|(_, region)| region.tone().round() as u8);
+
+    let mut res = from_label_map(&labels, img);
+    for (segment, tone) in res.iter_mut().zip(tones) {
+        segment.tone = tone;
+    }
+
+    res
+}
+
+/// Region holding the region `i`
+fn find(regions: &[Region], mut i: usize) -> usize {
+    while regions[i].parent != i {
+        i = regions[i].parent;
+    }
+
+    i
+}
+
+/// Moves the region `from` into the region `into`, the borders of both being added up
+fn merge(regions: &mut [Region], from: usize, into: usize) {
+    let neighbours = std::mem::take(&mut regions[from].neighbours);
+
+    for (other, length) in neighbours {
+        regions[other].neighbours.remove(&from);
+
+        if other != into {
+            *regions[other].neighbours.entry(into).or_insert(0) += length;
+            *regions[into].neighbours.entry(other).or_insert(0) += length;
+        }
+    }
+
+    regions[from].parent = into;
+    regions[into].area += regions[from].area;
+    regions[into].tone_sum += regions[from].tone_sum;
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayAlphaImage, LumaA};
+
+    use crate::{
+        helpers::Area,
+        segmentation::{Connectivity, ImgSegmentation},
+    };
+
+    use super::merge_small;
+
+    /// Dark and light halves, with about a tenth of the pixels replaced by random tones
+    fn noisy(width: u32, height: u32) -> GrayAlphaImage {
+        let mut state: u64 = 987654321;
+
+        GrayAlphaImage::from_fn(width, height, |x, _| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+            let random = (state >> 33) as u32;
+
+            let tone = if random.is_multiple_of(10) {
+                (random >> 8) as u8
+            } else if x < width / 2 {
+                60
+            } else {
+                180
+            };
+            LumaA([tone, 255])
+        })
+    }
+
+    #[test]
+    fn noise() {
+        let img = noisy(40, 30);
+
+        for connectivity in [Connectivity::Four, Connectivity::Eight] {
+            let segments = ImgSegmentation::segment_img(&img, connectivity).unwrap();
+            assert!(segments.len() > 50);
+
+            let merged = merge_small(segments, &img, 20, connectivity);
+
+            assert_eq!(merged.len(), 2);
+            assert_eq!(merged.iter().map(|s| s.seg.area()).sum::<usize>(), 40 * 30);
+            assert!(merged[0].tone.abs_diff(60) < 15);
+            assert!(merged[1].tone.abs_diff(180) < 15);
+            assert!(merged[0].centroid.0 < 20.0 && merged[1].centroid.0 > 20.0);
+        }
+    }
+
+    #[test]
+    fn closest_tone() {
+        // A single pixel between a dark and a light segment, closer to the dark one
+        let tones = [90, 90, 90, 100, 200, 200, 200, 200];
+        let img = GrayAlphaImage::from_fn(8, 1, |x, _| LumaA([tones[x as usize], 255]));
+
+        let segments = ImgSegmentation::segment_img(&img, Connectivity::Four).unwrap();
+        let merged = merge_small(segments, &img, 2, Connectivity::Four);
+
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].seg[&0], vec![0..=3]);
+        assert_eq!(merged[0].tone, 93);
+        assert_eq!(merged[1].tone, 200);
+        assert!((merged[0].centroid.0 - 1.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn isolated() {
+        // Two single pixels separated by transparency
+        let img = GrayAlphaImage::from_fn(3, 1, |x, _| LumaA([40, if x == 1 { 0 } else { 255 }]));
+
+        let segments = ImgSegmentation::segment_img(&img, Connectivity::Four).unwrap();
+        let merged = merge_small(segments, &img, 10, Connectivity::Four);
+
+        assert_eq!(merged.len(), 2);
+    }
+}
@@ -4,14 +4,14 @@ use crate::helpers::Overlaps;
 
 use super::Segment;
 
-impl Overlaps for RangeInclusive<u16> {
+impl Overlaps for RangeInclusive<u32> {
     #[inline]
     fn overlaps(&self, other: &Self) -> bool {
         self.end() >= other.start() && other.end() >= self.start()
     }
 }
 
-impl Overlaps for Vec<RangeInclusive<u16>> {
+impl Overlaps for Vec<RangeInclusive<u32>> {
     #[inline]
     fn overlaps(&self, other: &Self) -> bool {
         self.iter()
@@ -0,0 +1,400 @@
+use std::collections::VecDeque;
+
+use image::{DynamicImage, GrayAlphaImage, RgbaImage};
+
+use crate::{
+    colour::{distance, Colour, ColourSpace},
+    helpers::{Coordinates, CoordinatesF, SmallCoord},
+};
+
+use super::{
+    criterion::Criterion, Connectivity, ImageSegments, ImgSegmentation, SegmentationError,
+    Segmenter,
+};
+
+/// Simple linear iterative clustering: superpixels grown from a regular grid by k-means on the
+/// CIELAB colour and the position of the pixels
+pub struct Slic;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SlicParams {
+    /// Side of the square cells the superpixels start from, in pixels
+    pub size: u32,
+    /// Weight of the distance in the image against the colour distance, higher values giving
+    /// more regular superpixels
+    pub compactness: f64,
+    pub iterations: usize,
+    pub connectivity: Connectivity,
+}
+
+impl Default for SlicParams {
+    fn default() -> Self {
+        Self {
+            size: 16,
+            compactness: 10.0,
+            iterations: 10,
+            connectivity: Connectivity::Four,
+        }
+    }
+}
+
+/// Label of the pixels outside of every cluster
+const UNLABELED: u32 = u32::MAX;
+
+#[derive(Clone, Copy)]
+struct Cluster {
+    colour: Colour,
+    position: CoordinatesF,
+}
+
+/// Colours and labels of the pixels, in row order
+struct Clustering {
+    colours: Vec<Colour>,
+    visible: Vec<bool>,
+    labels: Vec<u32>,
+    width: u32,
+    height: u32,
+}
+
+impl Clustering {
+    #[inline]
+    fn index(&self, coords: Coordinates) -> usize {
+        coords.0 as usize + coords.1 as usize * self.width as usize
+    }
+
+    /// Squared colour change around a pixel
+    fn gradient(&self, (x, y): Coordinates) -> f64 {
+        let colour = |x: u32, y: u32| {
+            &self.colours[self.index((x.min(self.width - 1), y.min(self.height - 1)))]
+        };
+
+        let horizontal = distance(colour(x + 1, y), colour(x.saturating_sub(1), y));
+        let vertical = distance(colour(x, y + 1), colour(x, y.saturating_sub(1)));
+
+        horizontal.powi(2) + vertical.powi(2)
+    }
+
+    /// Starts of the cells along one side of the image, with their lengths
+    fn cells(length: u32, size: u32) -> impl Iterator<Item = (u32, u32)> {
+        (0..length)
+            .step_by(size as usize)
+            .map(move |start| (start, size.min(length - start)))
+    }
+
+    /// One cluster per cell with visible pixels, on the smoothest visible pixel around the centre
+    /// of the cell, or on its first visible pixel
+    fn seed(&self, size: u32) -> Vec<Cluster> {
+        let mut res = Vec::new();
+
+        for (y_0, height) in Self::cells(self.height, size) {
+            for (x_0, width) in Self::cells(self.width, size) {
+                let (c_x, c_y) = (x_0 + width / 2, y_0 + height / 2);
+
+                let around =
+                    (c_y.saturating_sub(1)..=(c_y + 1).min(self.height - 1)).flat_map(|y| {
+                        (c_x.saturating_sub(1)..=(c_x + 1).min(self.width - 1)).map(move |x| (x, y))
+                    });
+                let mut cell =
+                    (y_0..y_0 + height).flat_map(|y| (x_0..x_0 + width).map(move |x| (x, y)));
+
+                let seed = around
+                    .filter(|coords| self.visible[self.index(*coords)])
+                    .min_by(|a, b| self.gradient(*a).total_cmp(&self.gradient(*b)))
+                    .or_else(|| cell.find(|coords| self.visible[self.index(*coords)]));
+
+                if let Some(coords) = seed {
+                    res.push(Cluster {
+                        colour: self.colours[self.index(coords)],
+                        position: (coords.0 as f64, coords.1 as f64),
+                    });
+                }
+            }
+        }
+
+        res
+    }
+
+    /// Gives each visible pixel within reach the label of its closest cluster. Pixels out of
+    /// reach of every cluster keep their label.
+    fn assign(&mut self, clusters: &[Cluster], params: &SlicParams) {
+        let size = params.size as f64;
+        let weight = (params.compactness / size).powi(2);
+        let mut distances = vec![f64::INFINITY; self.labels.len()];
+
+        for (label, cluster) in clusters.iter().enumerate() {
+            let (c_x, c_y) = cluster.position;
+            let x_0 = (c_x - size).max(0.0).ceil() as u32;
+            let y_0 = (c_y - size).max(0.0).ceil() as u32;
+            let x_1 = ((c_x + size).floor() as u32).min(self.width - 1);
+            let y_1 = ((c_y + size).floor() as u32).min(self.height - 1);
+
+            for y in y_0..=y_1 {
+                for x in x_0..=x_1 {
+                    let i = self.index((x, y));
+                    if !self.visible[i] {
+                        continue;
+                    }
+
+                    let spatial = (x as f64 - c_x).powi(2) + (y as f64 - c_y).powi(2);
+                    let d = distance(&self.colours[i], &cluster.colour).powi(2) + spatial * weight;
+
+                    if d < distances[i] {
+                        distances[i] = d;
+                        self.labels[i] = label as u32;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Moves the clusters to the mean colour and position of their pixels
+    fn update(&self, clusters: &mut [Cluster]) {
+        let mut sums = vec![([0.0; 3], (0.0, 0.0), 0usize); clusters.len()];
+
+        for (i, label) in self.labels.iter().enumerate() {
+            if *label == UNLABELED {
+                continue;
+            }
+
+            let (colour, position, count) = &mut sums[*label as usize];
+            for (sum, c) in colour.iter_mut().zip(self.colours[i]) {
+                *sum += c;
+            }
+            position.0 += (i % self.width as usize) as f64;
+            position.1 += (i / self.width as usize) as f64;
+            *count += 1;
+        }
+
+        for (cluster, (colour, position, count)) in clusters.iter_mut().zip(sums) {
+            if count > 0 {
+                let count = count as f64;
+                cluster.colour = colour.map(|c| c / count);
+                cluster.position = (position.0 / count, position.1 / count);
+            }
+        }
+    }
+
+    /// Relabels the pixels so that each label is connected, merging the pieces smaller than
+    /// `min_area` into a piece touching them
+    fn enforce_connectivity(&mut self, min_area: usize) {
+        let mut res = vec![UNLABELED; self.labels.len()];
+        let mut next = 0;
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let start = self.index((x, y));
+                if !self.visible[start] || res[start] != UNLABELED {
+                    continue;
+                }
+
+                // Label of an already relabeled piece on the left or above
+                let adjacent = [(x.wrapping_sub(1), y), (x, y.wrapping_sub(1))]
+                    .into_iter()
+                    .filter(|(x, y)| *x < self.width && *y < self.height)
+                    .map(|coords| res[self.index(coords)])
+                    .find(|label| *label != UNLABELED);
+
+                let piece = self.flood(start, next, &mut res);
+
+                match adjacent {
+                    Some(adjacent) if piece.len() < min_area => {
+                        for i in piece {
+                            res[i] = adjacent;
+                        }
+                    }
+                    _ => next += 1,
+                }
+            }
+        }
+
+        self.labels = res;
+    }
+
+    /// Gives `label` to the pixels sharing the old label of `start` and connected to it by their
+    /// sides, returning their indices
+    fn flood(&self, start: usize, label: u32, res: &mut [u32]) -> Vec<usize> {
+        let old = self.labels[start];
+        let width = self.width as usize;
+        let mut piece = Vec::new();
+        let mut queue = VecDeque::from([start]);
+        res[start] = label;
+
+        while let Some(i) = queue.pop_front() {
+            piece.push(i);
+
+            let (x, y) = (i % width, i / width);
+            let neighbours = [
+                (x > 0).then(|| i - 1),
+                (x + 1 < width).then(|| i + 1),
+                (y > 0).then(|| i - width),
+                (y + 1 < self.height as usize).then(|| i + width),
+            ];
+
+            for n in neighbours.into_iter().flatten() {
+                if self.visible[n] && res[n] == UNLABELED && self.labels[n] == old {
+                    res[n] = label;
+                    queue.push_back(n);
+                }
+            }
+        }
+
+        piece
+    }
+}
+
+/// Pixels with the superpixel label of the seed, the tone of a segment being its mean tone
+struct SameLabel<'a> {
+    img: &'a GrayAlphaImage,
+    labels: &'a [u32],
+    label: u32,
+    sum: u64,
+    count: u64,
+}
+
+impl SameLabel<'_> {
+    #[inline]
+    fn label(&self, coords: Coordinates) -> u32 {
+        self.labels[coords.0 as usize + coords.1 as usize * self.img.width() as usize]
+    }
+}
+
+impl Criterion for SameLabel<'_> {
+    #[inline]
+    fn seed(&mut self, coords: Coordinates) {
+        self.label = self.label(coords);
+        self.sum = 0;
+        self.count = 0;
+    }
+
+    #[inline]
+    fn accepts(&self, coords: Coordinates) -> bool {
+        self.label(coords) == self.label
+    }
+
+    #[inline]
+    fn add(&mut self, coords: Coordinates) {
+        self.sum += self.img.get_pixel_s(coords)[0] as u64;
+        self.count += 1;
+    }
+
+    #[inline]
+    fn tone(&self) -> u8 {
+        (self.sum as f64 / self.count.max(1) as f64).round() as u8
+    }
+}
+
+impl<'a> Segmenter<'a> for Slic {
+    type Image = RgbaImage;
+    type Params = SlicParams;
+
+    fn segment(img: &'a RgbaImage, params: SlicParams) -> Result<ImageSegments, SegmentationError> {
+        let (width, height) = img.dimensions();
+
+        let luma = DynamicImage::ImageRgba8(img.clone()).to_luma_alpha8();
+        if width == 0 || height == 0 {
+            return ImgSegmentation::segment(&luma, params.connectivity);
+        }
+
+        let params = SlicParams {
+            size: params.size.max(1),
+            ..params
+        };
+
+        let mut clustering = Clustering {
+            colours: img
+                .pixels()
+                .map(|p| ColourSpace::Lab.from_rgb([p[0], p[1], p[2]]))
+                .collect(),
+            visible: img.pixels().map(|p| p[3] != 0).collect(),
+            labels: vec![UNLABELED; img.len() / 4],
+            width,
+            height,
+        };
+
+        let mut clusters = clustering.seed(params.size);
+        for _ in 0..params.iterations.max(1) {
+            clustering.assign(&clusters, &params);
+            clustering.update(&mut clusters);
+        }
+
+        let cell = params.size as usize * params.size as usize;
+        clustering.enforce_connectivity(cell / 4);
+
+        let criterion = SameLabel {
+            img: &luma,
+            labels: &clustering.labels,
+            label: UNLABELED,
+            sum: 0,
+            count: 0,
+        };
+
+        ImgSegmentation::segment_with(&luma, criterion, params.connectivity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use crate::{
+        helpers::Area,
+        segmentation::{Connectivity, ImgSegmentation, Segmenter},
+    };
+
+    use super::{Slic, SlicParams};
+
+    #[test]
+    fn uniform() {
+        let img = RgbaImage::from_pixel(32, 32, Rgba([90, 140, 60, 255]));
+
+        let segments = Slic::segment(
+            &img,
+            SlicParams {
+                size: 8,
+                ..SlicParams::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(segments.len(), 16);
+        assert_eq!(
+            segments.iter().map(|s| s.seg.area()).sum::<usize>(),
+            32 * 32
+        );
+        assert!(segments
+            .iter()
+            .all(|s| (36..=100).contains(&s.seg.area()) && s.tone == segments[0].tone));
+    }
+
+    /// Noisy red and blue halves, with a transparent band between them
+    fn halves() -> RgbaImage {
+        RgbaImage::from_fn(40, 24, |x, y| {
+            let noise = ((x * 7 + y * 13) % 9) as u8;
+
+            match x {
+                0..=16 => Rgba([200 + noise, 30, 30, 255]),
+                17..=19 => Rgba([0, 0, 0, 0]),
+                _ => Rgba([30, 30, 200 + noise, 255]),
+            }
+        })
+    }
+
+    #[test]
+    fn colour_edges() {
+        let img = halves();
+        let segments = Slic::segment(&img, SlicParams::default()).unwrap();
+        let luma = image::DynamicImage::ImageRgba8(img).to_luma_alpha8();
+        let exact = ImgSegmentation::segment(&luma, Connectivity::Four).unwrap();
+
+        assert!(segments.len() < exact.len());
+        for segment in segments.iter() {
+            let mut xs = segment.seg.values().flatten().flat_map(|r| r.clone());
+
+            assert!(xs.clone().all(|x| x <= 16) || xs.all(|x| x >= 20));
+        }
+        assert_eq!(
+            segments.iter().map(|s| s.seg.area()).sum::<usize>(),
+            (40 - 3) * 24
+        );
+    }
+}
@@ -0,0 +1,401 @@
+use image::{DynamicImage, GrayAlphaImage, GrayImage, Rgba, RgbaImage};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use crate::segmentation::Segment;
+
+/// Settings of [`TextureSynthesis`]
+pub struct SynthesisParams {
+    pub patch_size: u32,
+    /// Pixels shared by neighbouring patches, where the seams are cut
+    pub overlap: u32,
+    /// Source patches sampled for each block
+    pub candidates: usize,
+    /// 0 -> only matches the overlap with the patches already placed
+    /// 1 -> only matches the target luminance
+    pub guidance_weight: f64,
+    pub seed: u64,
+}
+
+impl Default for SynthesisParams {
+    fn default() -> Self {
+        Self {
+            patch_size: 16,
+            overlap: 4,
+            candidates: 64,
+            guidance_weight: 0.6,
+            seed: 0,
+        }
+    }
+}
+
+/// Guided image quilting: fills the masked pixels of a target luminance map with patches of a
+/// source texture, cutting minimum error seams between them.
+pub struct TextureSynthesis<'a> {
+    source: &'a RgbaImage,
+    source_luma: GrayImage,
+    target: &'a GrayAlphaImage,
+    patch_size: u32,
+    overlap: u32,
+    candidates: usize,
+    guidance_weight: f64,
+    rng: ChaCha8Rng,
+    out: RgbaImage,
+    filled: Vec<bool>,
+}
+
+impl<'a> TextureSynthesis<'a> {
+    /// The output has the dimensions of `target`, and only the pixels of `mask` are opaque
+    pub fn synthesize(
+        source: &'a RgbaImage,
+        target: &'a GrayAlphaImage,
+        mask: &Segment,
+        params: &SynthesisParams,
+    ) -> RgbaImage {
+        Self::synthesize_with_progress(source, target, mask, params, || true)
+            .expect("synthesis without a progress callback is never stopped")
+    }
+
+    /// Same as [`TextureSynthesis::synthesize`], calling `progress` before each patch.
+    /// Gives up with `None` as soon as `progress` returns false.
+    pub fn synthesize_with_progress<F>(
+        source: &'a RgbaImage,
+        target: &'a GrayAlphaImage,
+        mask: &Segment,
+        params: &SynthesisParams,
+        mut progress: F,
+    ) -> Option<RgbaImage>
+    where
+        F: FnMut() -> bool,
+    {
+        let (width, height) = target.dimensions();
+        let patch_size = params
+            .patch_size
+            .min(source.width())
+            .min(source.height())
+            .max(1);
+
+        let mut this = Self {
+            source,
+            source_luma: DynamicImage::ImageRgba8(source.clone()).to_luma8(),
+            target,
+            patch_size,
+            overlap: params.overlap.min(patch_size / 2),
+            candidates: params.candidates.max(1),
+            guidance_weight: params.guidance_weight.clamp(0.0, 1.0),
+            rng: ChaCha8Rng::seed_from_u64(params.seed),
+            out: RgbaImage::new(width, height),
+            filled: vec![false; width as usize * height as usize],
+        };
+
+        let mut in_mask = vec![false; width as usize * height as usize];
+        for (y, ranges) in mask.iter() {
+            for x in ranges.iter().flat_map(|range| range.clone()) {
+                if x < width && *y < height {
+                    in_mask[x as usize + *y as usize * width as usize] = true;
+                }
+            }
+        }
+
+        if source.width() == 0 || source.height() == 0 {
+            return Some(this.out);
+        }
+
+        let step = (this.patch_size - this.overlap) as usize;
+
+        for ty in (0..height).step_by(step) {
+            for tx in (0..width).step_by(step) {
+                let block = this.block(tx, ty);
+
+                let touches_mask = (ty..ty + block.1).any(|y| {
+                    (tx..tx + block.0).any(|x| in_mask[x as usize + y as usize * width as usize])
+                });
+
+                if touches_mask {
+                    if !progress() {
+                        return None;
+                    }
+
+                    let (sx, sy) = this.best_candidate(tx, ty, block);
+                    this.paste(tx, ty, sx, sy, block);
+                }
+            }
+        }
+
+        for (x, y, pixel) in this.out.enumerate_pixels_mut() {
+            pixel[3] = if in_mask[x as usize + y as usize * width as usize] {
+                target.get_pixel(x, y)[1]
+            } else {
+                0
+            };
+        }
+
+        Some(this.out)
+    }
+
+    /// Patch dimensions at a target position, clipped by the target borders
+    #[inline]
+    fn block(&self, tx: u32, ty: u32) -> (u32, u32) {
+        (
+            self.patch_size.min(self.out.width() - tx),
+            self.patch_size.min(self.out.height() - ty),
+        )
+    }
+
+    #[inline]
+    fn is_filled(&self, x: u32, y: u32) -> bool {
+        self.filled[x as usize + y as usize * self.out.width() as usize]
+    }
+
+    /// Squared colour difference between a source pixel and what was already placed
+    #[inline]
+    fn overlap_error(&self, sx: u32, sy: u32, x: u32, y: u32) -> f64 {
+        let src = self.source.get_pixel(sx, sy);
+        let out = self.out.get_pixel(x, y);
+
+        (0..3).fold(0.0, |acc, c| {
+            acc + (src[c] as f64 - out[c] as f64).powi(2) / 3.0
+        })
+    }
+
+    fn best_candidate(&mut self, tx: u32, ty: u32, block: (u32, u32)) -> (u32, u32) {
+        let max_x = self.source.width() - block.0;
+        let max_y = self.source.height() - block.1;
+
+        let mut best = (0, 0);
+        let mut best_cost = f64::INFINITY;
+
+        for _ in 0..self.candidates {
+            let sx = self.rng.gen_range(0..=max_x);
+            let sy = self.rng.gen_range(0..=max_y);
+
+            let mut guidance = 0.0;
+            let mut overlap = 0.0;
+            let mut overlap_pixels = 0;
+
+            for dy in 0..block.1 {
+                for dx in 0..block.0 {
+                    let (x, y) = (tx + dx, ty + dy);
+
+                    let target_luma = self.target.get_pixel(x, y)[0] as f64;
+                    let source_luma = self.source_luma.get_pixel(sx + dx, sy + dy)[0] as f64;
+                    guidance += (target_luma - source_luma).powi(2);
+
+                    if self.is_filled(x, y) {
+                        overlap += self.overlap_error(sx + dx, sy + dy, x, y);
+                        overlap_pixels += 1;
+                    }
+                }
+            }
+
+            guidance /= (block.0 * block.1) as f64;
+            if overlap_pixels > 0 {
+                overlap /= overlap_pixels as f64;
+            }
+
+            let cost = self.guidance_weight * guidance + (1.0 - self.guidance_weight) * overlap;
+
+            if cost < best_cost {
+                best_cost = cost;
+                best = (sx, sy);
+            }
+        }
+
+        best
+    }
+
+    /// Minimum error path across the overlap, as the first index taken from the new patch
+    fn seam(errors: &[Vec<f64>]) -> Vec<usize> {
+        let width = errors.first().map_or(0, Vec::len);
+        if width == 0 {
+            return vec![0; errors.len()];
+        }
+
+        let mut cost = errors.to_vec();
+        for i in 1..cost.len() {
+            for j in 0..width {
+                let previous = cost[i - 1][j.saturating_sub(1)..(j + 2).min(width)]
+                    .iter()
+                    .copied()
+                    .fold(f64::INFINITY, f64::min);
+                cost[i][j] += previous;
+            }
+        }
+
+        let mut res: Vec<usize> = vec![0; cost.len()];
+        for i in (0..cost.len()).rev() {
+            let range = match res.get(i + 1) {
+                Some(&next) => next.saturating_sub(1)..(next + 2).min(width),
+                None => 0..width,
+            };
+
+            res[i] = range
+                .min_by(|a, b| cost[i][*a].total_cmp(&cost[i][*b]))
+                .unwrap();
+        }
+
+        res
+    }
+
+    fn paste(&mut self, tx: u32, ty: u32, sx: u32, sy: u32, block: (u32, u32)) {
+        let overlap_x = self.overlap.min(block.0) as usize;
+        let overlap_y = self.overlap.min(block.1) as usize;
+
+        // Vertical seam through the left overlap, one column per row
+        let left: Vec<Vec<f64>> = (0..block.1)
+            .map(|dy| {
+                (0..overlap_x as u32)
+                    .map(|dx| self.seam_error(tx + dx, ty + dy, sx + dx, sy + dy))
+                    .collect()
+            })
+            .collect();
+        // Horizontal seam through the top overlap, one row per column
+        let top: Vec<Vec<f64>> = (0..block.0)
+            .map(|dx| {
+                (0..overlap_y as u32)
+                    .map(|dy| self.seam_error(tx + dx, ty + dy, sx + dx, sy + dy))
+                    .collect()
+            })
+            .collect();
+
+        let left_seam = Self::seam(&left);
+        let top_seam = Self::seam(&top);
+
+        for dy in 0..block.1 {
+            for dx in 0..block.0 {
+                let (x, y) = (tx + dx, ty + dy);
+
+                let past_left = dx as usize >= overlap_x || dx as usize >= left_seam[dy as usize];
+                let past_top = dy as usize >= overlap_y || dy as usize >= top_seam[dx as usize];
+
+                if !self.is_filled(x, y) || (past_left && past_top) {
+                    let pixel = self.source.get_pixel(sx + dx, sy + dy);
+                    self.out
+                        .put_pixel(x, y, Rgba([pixel[0], pixel[1], pixel[2], u8::MAX]));
+
+                    let width = self.out.width() as usize;
+                    self.filled[x as usize + y as usize * width] = true;
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn seam_error(&self, x: u32, y: u32, sx: u32, sy: u32) -> f64 {
+        if self.is_filled(x, y) {
+            self.overlap_error(sx, sy, x, y)
+        } else {
+            0.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayAlphaImage, LumaA, Rgba, RgbaImage};
+
+    use crate::segmentation::Segment;
+
+    use super::{SynthesisParams, TextureSynthesis};
+
+    /// Left half dark and reddish, right half light and bluish, with some noise
+    fn source() -> RgbaImage {
+        RgbaImage::from_fn(64, 64, |x, y| {
+            let noise = ((x * 7 + y * 13) % 11) as u8;
+
+            if x < 32 {
+                Rgba([60 + noise, 20, 20, 255])
+            } else {
+                Rgba([180, 200, 220 + noise, 255])
+            }
+        })
+    }
+
+    fn full_mask(width: u32, height: u32) -> Segment {
+        (0..height).map(|y| (y, vec![0..=width - 1])).collect()
+    }
+
+    #[test]
+    fn deterministic() {
+        let source = source();
+        let target = GrayAlphaImage::from_pixel(40, 30, LumaA([100, 255]));
+        let mask = full_mask(40, 30);
+        let params = SynthesisParams {
+            seed: 42,
+            ..SynthesisParams::default()
+        };
+
+        let first = TextureSynthesis::synthesize(&source, &target, &mask, &params);
+        let second = TextureSynthesis::synthesize(&source, &target, &mask, &params);
+
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn stopped() {
+        let source = source();
+        let target = GrayAlphaImage::from_pixel(40, 30, LumaA([100, 255]));
+        let mask = full_mask(40, 30);
+        let params = SynthesisParams::default();
+
+        let mut patches = 0;
+        let res =
+            TextureSynthesis::synthesize_with_progress(&source, &target, &mask, &params, || {
+                patches += 1;
+                patches < 3
+            });
+
+        assert!(res.is_none());
+        assert_eq!(patches, 3);
+
+        let mut patches = 0;
+        let res =
+            TextureSynthesis::synthesize_with_progress(&source, &target, &mask, &params, || {
+                patches += 1;
+                true
+            });
+
+        assert_eq!(
+            res,
+            Some(TextureSynthesis::synthesize(
+                &source, &target, &mask, &params
+            ))
+        );
+        assert!(patches > 3);
+    }
+
+    #[test]
+    fn fills_only_mask() {
+        let source = source();
+        let target = GrayAlphaImage::from_pixel(40, 30, LumaA([100, 255]));
+        let mask = Segment::from([(3, vec![2..=10, 20..=25]), (4, vec![5..=30])]);
+
+        let res =
+            TextureSynthesis::synthesize(&source, &target, &mask, &SynthesisParams::default());
+
+        for (x, y, pixel) in res.enumerate_pixels() {
+            let masked = mask
+                .get(&y)
+                .is_some_and(|ranges| ranges.iter().any(|r| r.contains(&x)));
+
+            assert_eq!(pixel[3] == 255, masked, "({x}, {y})");
+        }
+    }
+
+    #[test]
+    fn follows_guidance() {
+        let source = source();
+        // Dark on top, light on the bottom
+        let target =
+            GrayAlphaImage::from_fn(48, 48, |_, y| LumaA([if y < 24 { 40 } else { 200 }, 255]));
+        let params = SynthesisParams {
+            guidance_weight: 1.0,
+            ..SynthesisParams::default()
+        };
+
+        let res = TextureSynthesis::synthesize(&source, &target, &full_mask(48, 48), &params);
+
+        assert!(res.get_pixel(10, 4)[0] < 100);
+        assert!(res.get_pixel(10, 44)[2] > 200);
+    }
+}